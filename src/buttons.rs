@@ -29,7 +29,7 @@ impl Buttons {
         self.step.set(bit as u8, on);
     }
 
-    pub fn is_clear(&self) -> bool {
+    pub fn is_released(&self) -> bool {
         *self.top == 0 && *self.step == 0
     }
 
@@ -37,6 +37,14 @@ impl Buttons {
         self.top.is(8)
     }
 
+    pub fn is_copy(&self) -> bool {
+        self.top.is(9)
+    }
+
+    pub fn is_clear(&self) -> bool {
+        self.top.is(10)
+    }
+
     pub fn is_vel(&self) -> bool {
         self.top.is(11)
     }
@@ -0,0 +1,124 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::{free, Mutex};
+
+use crate::hal::exti::{Event, ExtiExt};
+use crate::hal::gpio::SignalEdge;
+use crate::hal::stm32::{self as pac, interrupt};
+use crate::scan;
+use crate::state::Oper;
+use crate::{InClock, InReset, Time};
+
+/// Hands the capture over to the interrupt, which takes it on the first run.
+static CAPTURE: Mutex<RefCell<Option<Capture>>> = Mutex::new(RefCell::new(None));
+
+/// Captures clock and reset edges with EXTI, so they are timestamped when they
+/// happen rather than when the input is next scanned.
+pub struct Capture {
+    exti: pac::EXTI,
+    _in_clock: InClock,
+    _in_reset: InReset,
+    clock_interval: EdgeInterval,
+}
+
+impl Capture {
+    /// Start listening to the inputs. Must be started after the scanner, which
+    /// owns the clock.
+    pub fn start(in_clock: InClock, in_reset: InReset, mut exti: pac::EXTI) {
+        // "Falling" because the input is inverted. Reset listens to both edges
+        // since it can be used as a run/stop gate.
+        let in_clock = in_clock.listen(SignalEdge::Falling, &mut exti);
+        let in_reset = in_reset.listen(SignalEdge::All, &mut exti);
+
+        let capture = Capture {
+            exti,
+            _in_clock: in_clock,
+            _in_reset: in_reset,
+            clock_interval: EdgeInterval::default(),
+        };
+
+        free(|cs| {
+            CAPTURE.borrow(cs).replace(Some(capture));
+        });
+
+        // EXTI4_15 stays at the default, highest, priority so it preempts the scan.
+        // NOTE(unsafe) the capture state is set up before the interrupt is unmasked.
+        unsafe { pac::NVIC::unmask(pac::Interrupt::EXTI4_15) };
+    }
+
+    /// Timestamp pending edges. The shared state is only locked to take the
+    /// time and to queue the operations.
+    fn run(&mut self) {
+        let now = scan::now();
+
+        // Handle reset before clock, in case they come at the same time, the reset
+        // should be handled in AppState before the clock.
+        let reset = if self.exti.is_pending(Event::GPIO13, SignalEdge::Falling) {
+            self.exti.unpend(Event::GPIO13);
+            Some(Oper::Reset)
+        } else if self.exti.is_pending(Event::GPIO13, SignalEdge::Rising) {
+            self.exti.unpend(Event::GPIO13);
+            Some(Oper::ResetEnd)
+        } else {
+            None
+        };
+
+        let clock = if self.exti.is_pending(Event::GPIO14, SignalEdge::Falling) {
+            self.exti.unpend(Event::GPIO14);
+            self.clock_interval.edge(now).map(Oper::Clock)
+        } else {
+            None
+        };
+
+        free(|cs| {
+            scan::with_shared(cs, |shared| {
+                for oper in [reset, clock].into_iter().flatten() {
+                    shared.oper_queue.push(oper);
+                }
+            })
+        });
+    }
+}
+
+#[interrupt]
+fn EXTI4_15() {
+    static mut CAPTURE_OWNED: Option<Capture> = None;
+
+    let capture =
+        CAPTURE_OWNED.get_or_insert_with(|| free(|cs| CAPTURE.borrow(cs).take()).expect("capture"));
+    capture.run();
+}
+
+/// Interval between timestamped edges.
+#[derive(Default)]
+pub struct EdgeInterval {
+    last: Option<Time>,
+}
+
+impl EdgeInterval {
+    /// Register an edge. Returns the interval since the previous edge, if there was one.
+    pub fn edge(&mut self, now: Time) -> Option<Time> {
+        let interval = self.last.map(|last| now - last);
+        self.last = Some(now);
+        interval
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn edge_interval() {
+        let mut edges = EdgeInterval::default();
+
+        // First edge has nothing to measure from.
+        assert!(edges.edge(Time::from_micros(1_000)).is_none());
+
+        assert!(edges.edge(Time::from_micros(21_000)) == Some(Time::from_micros(20_000)));
+        assert!(edges.edge(Time::from_micros(41_500)) == Some(Time::from_micros(20_500)));
+
+        // Equal timestamps give a zero interval.
+        assert!(edges.edge(Time::from_micros(41_500)) == Some(Time::from_micros(0)));
+    }
+}
@@ -0,0 +1,50 @@
+use crate::track::TrackStep;
+
+/// What is currently held in the clipboard.
+///
+/// Parts and tracks are held by reference to avoid keeping a second copy of
+/// the (large) step data in RAM. The data is read at paste time.
+#[derive(Default, Clone, Copy)]
+pub enum Clip {
+    #[default]
+    Empty,
+
+    /// A single step.
+    Step(TrackStep),
+
+    /// 16 steps. Track and part index.
+    Part(usize, usize),
+
+    /// An entire track, steps and params. Track index.
+    Track(usize),
+}
+
+#[derive(Default)]
+pub struct Clipboard {
+    clip: Clip,
+
+    /// Set when the copy button goes down. The first button pressed after that
+    /// is the source (copy), any following presses are targets (paste).
+    copy_next: bool,
+}
+
+impl Clipboard {
+    /// Start a new copy gesture.
+    pub fn begin(&mut self) {
+        self.copy_next = true;
+    }
+
+    /// Whether the next press should copy, rather than paste.
+    pub fn is_copy_next(&self) -> bool {
+        self.copy_next
+    }
+
+    pub fn copy(&mut self, clip: Clip) {
+        self.clip = clip;
+        self.copy_next = false;
+    }
+
+    pub fn clip(&self) -> Clip {
+        self.clip
+    }
+}
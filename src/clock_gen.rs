@@ -0,0 +1,193 @@
+use crate::settings::{ClockSource, ResetMode, Settings};
+use crate::state::{time_div, time_mul, Oper, CLOCK_LOSS_FACTOR};
+use crate::Time;
+
+/// Time without external clock before falling back to the internal clock.
+const EXTERNAL_TIMEOUT_MILLIS: i64 = 2000;
+
+/// Internal clock generator. Produces the same Oper::Clock as the external
+/// clock input, so AppState doesn't need to care about the source.
+///
+/// Also divides the external clock from the input resolution (PPQN) down to
+/// steps, or multiplies it when there are more steps than pulses per beat.
+#[derive(Default)]
+pub struct ClockGen {
+    /// Time of the last external clock.
+    last_external: Option<Time>,
+
+    /// External pulses until the next step. 0 means the next pulse is a step.
+    pulse_count: u8,
+
+    /// Interval of the last external pulse, unless it came after a gap.
+    last_pulse: Option<Time>,
+
+    /// Steps still to come between two external pulses.
+    multiply: Option<Multiply>,
+
+    /// Running internal clock.
+    internal: Option<Internal>,
+}
+
+struct Multiply {
+    /// Time of the external pulse.
+    pulse: Time,
+
+    /// Interval between the steps.
+    interval: Time,
+
+    /// Steps done since the pulse, including the pulse itself.
+    count: u8,
+
+    /// Steps per pulse.
+    steps: u8,
+}
+
+struct Internal {
+    /// Time counting started.
+    start: Time,
+
+    /// Number of pulses since start.
+    count: i64,
+
+    /// Interval the pulses are counted with.
+    interval: i64,
+}
+
+impl ClockGen {
+    /// Filter operations from input. External clocks are divided down to steps,
+    /// and dropped while the internal clock is forced.
+    pub fn filter(&mut self, now: Time, oper: Oper, settings: &Settings) -> Option<Oper> {
+        match oper {
+            Oper::Clock(interval) => {
+                self.last_external = Some(now);
+
+                if settings.clock_source == ClockSource::Internal {
+                    return None;
+                }
+
+                // After a gap, like when the internal clock took over, the interval is
+                // the gap rather than the tempo. Pass it on as unknown.
+                let gap = interval > Time::from_millis(EXTERNAL_TIMEOUT_MILLIS)
+                    || self
+                        .last_pulse
+                        .is_some_and(|last| interval > time_mul(last, CLOCK_LOSS_FACTOR));
+                self.last_pulse = (!gap).then_some(interval);
+                let interval = if gap { Time::default() } else { interval };
+
+                let steps = settings.steps_per_pulse();
+                if steps > 1 && gap {
+                    // Nothing to spread the steps over until the next pulse.
+                    self.multiply = None;
+                    return Some(Oper::Clock(interval));
+                }
+
+                if steps > 1 {
+                    // The pulse is the first step, the rest are spread out until the
+                    // next pulse by tick().
+                    let step_interval = time_div(interval, steps as u64);
+                    self.multiply = Some(Multiply {
+                        pulse: now,
+                        interval: step_interval,
+                        count: 1,
+                        steps,
+                    });
+                    return Some(Oper::Clock(step_interval));
+                }
+
+                let pulses = settings.pulses_per_step();
+                let is_step = self.pulse_count == 0;
+                self.pulse_count = (self.pulse_count + 1) % pulses;
+
+                // Every pulse, scaled to a step, goes to the tempo detection, so it
+                // averages over all pulses rather than just the steps.
+                let step_interval = time_mul(interval, pulses as u64);
+
+                if is_step {
+                    Some(Oper::Clock(step_interval))
+                } else {
+                    Some(Oper::Tempo(step_interval))
+                }
+            }
+
+            Oper::Reset => {
+                if matches!(
+                    settings.reset_mode,
+                    ResetMode::NextClock | ResetMode::Immediate | ResetMode::RunStop
+                ) {
+                    // Next pulse is a step.
+                    self.pulse_count = 0;
+                    self.multiply = None;
+                }
+
+                Some(oper)
+            }
+
+            _ => Some(oper),
+        }
+    }
+
+    /// Produce the next internal clock, if it's time for one.
+    pub fn tick(&mut self, now: Time, settings: &Settings) -> Option<Oper> {
+        let use_internal = match settings.clock_source {
+            ClockSource::Auto => self
+                .last_external
+                .map(|t| now - t > Time::from_millis(EXTERNAL_TIMEOUT_MILLIS))
+                .unwrap_or(true),
+            ClockSource::Internal => true,
+            ClockSource::External => false,
+        };
+
+        if !use_internal {
+            self.internal = None;
+            return self.tick_multiply(now);
+        }
+
+        self.multiply = None;
+
+        let interval = settings.clock_interval_micros();
+
+        let Some(int) = &mut self.internal else {
+            // First pulse right away.
+            self.internal = Some(Internal {
+                start: now,
+                count: 0,
+                interval,
+            });
+            return Some(Oper::Clock(Time::from_micros(interval)));
+        };
+
+        if int.interval != interval {
+            // Tempo changed, count from here with the new interval.
+            int.start = now;
+            int.count = 0;
+            int.interval = interval;
+        }
+
+        // Pulses are counted from the start to not drift with the loop time.
+        if now - int.start < Time::from_micros(interval * (int.count + 1)) {
+            return None;
+        }
+
+        int.count += 1;
+
+        Some(Oper::Clock(Time::from_micros(interval)))
+    }
+
+    /// Produce the next step between external pulses, if it's time for one.
+    fn tick_multiply(&mut self, now: Time) -> Option<Oper> {
+        let m = self.multiply.as_mut()?;
+
+        if m.count >= m.steps {
+            self.multiply = None;
+            return None;
+        }
+
+        if now - m.pulse < time_mul(m.interval, m.count as u64) {
+            return None;
+        }
+
+        m.count += 1;
+
+        Some(Oper::Clock(m.interval))
+    }
+}
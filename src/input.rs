@@ -3,18 +3,14 @@ use crate::state::Oper;
 use alg::clock::Time;
 use alg::encoder::{Encoder, QuadratureSource};
 use alg::input::{DebounceDigitalInput, DeltaInput, DigitalEdgeInput};
-use alg::input::{DigitalInput, Edge, EdgeInput, HiLo};
+use alg::input::{DigitalInput, EdgeInput, HiLo};
+use alg::ring_buf::RingBuf;
 
-use crate::{
-    Col, InClock, InReset, OperQueue, Row, Row1RotA, Row1RotB, Row1Swl, Row1Swr, Row2RotA,
-};
+use crate::{Col, Row, Row1RotA, Row1RotB, Row1Swl, Row1Swr, Row2RotA};
 use crate::{Row2RotB, Row2Swl, Row2Swr, Row3Swl, Row4Swl, Row5Swl, CLOCK};
 
 /// Holder of input for the app.
 pub struct AppInput {
-    pub in_clock: DigitalIn<InClock>,
-    pub in_reset: DigitalIn<InReset>,
-
     pub rot_row1: Encoder<QuadSource<Row1RotA, Row1RotB>>,
     pub rot_row2: Encoder<QuadSource<Row2RotA, Row2RotB>>,
 
@@ -26,27 +22,16 @@ pub struct AppInput {
     pub swl_row3: PushButton<Row3Swl>,
     pub swl_row4: PushButton<Row4Swl>,
     pub swl_row5: PushButton<Row5Swl>,
-
-    pub last_clock: Option<Time<{ CLOCK }>>,
 }
 
 impl AppInput {
-    pub fn read_input(&mut self, now: Time<{ CLOCK }>, col: Col, oper_queue: &mut OperQueue) {
-        // Handle reset before clock, in case we handle them at the same time, the reset
-        // should be handled in AppState before the clock.
-
-        // "Falling" because the input is inverted
-        if matches!(self.in_reset.tick(now), Some(Edge::Falling(_))) {
-            oper_queue.push(Oper::Reset);
-        }
-
-        // "Falling" because the input is inverted
-        if matches!(self.in_clock.tick(now), Some(Edge::Falling(_))) {
-            if let Some(last) = self.last_clock {
-                let interval = now - last;
-                oper_queue.push(Oper::Clock(interval));
-            }
-        }
+    pub fn read_input<const N: usize>(
+        &mut self,
+        now: Time<{ CLOCK }>,
+        col: Col,
+        oper_queue: &mut RingBuf<Oper, N>,
+    ) {
+        // Clock and reset are captured by interrupt, see capture.rs.
 
         // Row 3-4-5 only has column 5,6,7,8.
         if *col >= 4 {
@@ -94,8 +79,6 @@ impl AppInput {
     }
 }
 
-pub type DigitalIn<A> = DigitalEdgeInput<PinDigitalIn<A>, { CLOCK }>;
-
 pub type PushButton<A> =
     DigitalEdgeInput<DebounceDigitalInput<PinDigitalIn<A>, { CLOCK }>, { CLOCK }>;
 
@@ -1,4 +1,5 @@
 use crate::flip_pin::FlipPinExt;
+use crate::Time;
 use crate::{Col1, Col2, Col3, Col4, Col5, Col6, Col7, Col8};
 use crate::{Row1Led, Row2Led, Row3Led, Row4Led, Row5Led};
 
@@ -56,12 +57,88 @@ impl LedGrid {
     }
 }
 
+/// Brightness of a LED. The number of sub-slots of the scan dwell the LED is lit.
+pub type Level = u8;
+
+/// Number of sub-slots in a scan dwell, i.e. full brightness.
+pub const LEVEL_MAX: Level = 4;
+
 #[derive(Default, Clone, Copy, PartialEq, Eq)]
 pub enum BiLed {
     #[default]
     Off,
     Red,
     Grn,
+    /// Both red and green, lit in alternating scan slots.
+    Amber,
+    /// Blinking on/off with even duty.
+    Blink(LedColor, Rate),
+    /// Short flash once per period.
+    Pulse(LedColor, Rate),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LedColor {
+    Red,
+    Grn,
+    Amber,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Rate {
+    /// 1 Hz
+    Slow,
+    /// 2 Hz
+    Medium,
+    /// 4 Hz
+    Fast,
+}
+
+impl Rate {
+    fn period_micros(&self) -> i64 {
+        // All periods must divide a second, since we only look at the subsec.
+        match self {
+            Rate::Slow => 1_000_000,
+            Rate::Medium => 500_000,
+            Rate::Fast => 250_000,
+        }
+    }
+}
+
+impl From<LedColor> for BiLed {
+    fn from(value: LedColor) -> Self {
+        match value {
+            LedColor::Red => BiLed::Red,
+            LedColor::Grn => BiLed::Grn,
+            LedColor::Amber => BiLed::Amber,
+        }
+    }
+}
+
+impl BiLed {
+    /// Resolve blink/pulse to a steady state for the given time.
+    fn resolve(&self, now: Time) -> BiLed {
+        let (color, rate, duty) = match *self {
+            BiLed::Blink(color, rate) => (color, rate, 2),
+            BiLed::Pulse(color, rate) => (color, rate, 8),
+            _ => return *self,
+        };
+
+        let period = rate.period_micros();
+        if now.subsec_micros() % period < period / duty {
+            color.into()
+        } else {
+            BiLed::Off
+        }
+    }
+
+    /// Whether this LED is lit in the scan slot (Red or Grn).
+    fn is_lit_in(&self, slot: BiLed, now: Time) -> bool {
+        match self.resolve(now) {
+            BiLed::Amber => slot != BiLed::Off,
+            x => x == slot,
+        }
+    }
 }
 
 impl LedGrid {
@@ -84,7 +161,19 @@ impl LedGrid {
         }
     }
 
-    pub fn set_leds(&mut self, row: usize, on: BiLed, leds: &[BiLed; 8]) {
+    /// Light the row for a scan slot. `on` is the slot, either Red or Grn.
+    ///
+    /// The slot is divided into sub-slots, and `sub` is the current one. LEDs
+    /// with a level lower or equal to `sub` are off for the rest of the slot.
+    pub fn set_leds(
+        &mut self,
+        now: Time,
+        row: usize,
+        on: BiLed,
+        leds: &[BiLed; 8],
+        levels: &[Level; 8],
+        sub: Level,
+    ) {
         if self.col_mode {
             self.col_mode = false;
         }
@@ -100,12 +189,12 @@ impl LedGrid {
             }
         }
 
-        for (c, led) in self.cols().iter_mut().zip(leds.iter()) {
-            if on == BiLed::Off || *led != on {
+        for ((c, led), level) in self.cols().iter_mut().zip(leds.iter()).zip(levels.iter()) {
+            if on == BiLed::Off || *level <= sub || !led.is_lit_in(on, now) {
                 c.disable();
             } else {
-                // led == on
-                let high = matches!(*led, BiLed::Grn);
+                // led is lit in the slot
+                let high = matches!(on, BiLed::Grn);
                 c.set_output(high);
             }
         }
@@ -1,15 +1,17 @@
-#![no_std]
-#![no_main]
+// Host tests: cargo test --target x86_64-unknown-linux-gnu
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
 #[macro_use]
 extern crate defmt;
 
 use core::ops::Deref;
 
-use alg::clock::{self, Clock};
+use alg::clock;
 use alg::encoder::Encoder;
 use alg::input::DigitalInput;
 use alg::ring_buf::RingBuf;
+#[cfg(not(test))]
 use cortex_m_rt::entry;
 use hal::gpio::{gpioa, gpiob, gpioc, gpiod, gpiof};
 use hal::gpio::{DefaultMode, Floating, Input, OpenDrain, Output, PushPull};
@@ -17,29 +19,41 @@ use hal::prelude::*;
 use hal::stm32::{self as pac};
 use hal::time::Hertz;
 use input::QuadSource;
-use led_grid::{BiLed, LedGridPins};
+use led_grid::{BiLed, LedGridPins, LEVEL_MAX};
 use state::Oper;
 use stm32g0xx_hal as hal;
 
+use crate::capture::Capture;
+use crate::clock_gen::ClockGen;
 use crate::flip_pin::{FlipPin, IntoFlipPin};
 use crate::input::{AppInput, PinDigitalIn};
 use crate::led_grid::LedGrid;
+use crate::scan::{AppClock, Scanner, Shared};
 use crate::state::AppState;
 
 mod buttons;
+mod capture;
+mod clipboard;
+mod clock_gen;
 mod flip_pin;
 mod input;
 mod led_grid;
 mod mstate;
 mod music;
+mod rng;
+mod scan;
+mod settings;
 mod state;
 mod track;
+mod transform;
 mod val;
+mod view;
 
 // Setup logging via defmt_rtt. "rtt" is "real time transfer"
 use defmt_rtt as _;
 
 // Log via defmt on panic.
+#[cfg(not(test))]
 use panic_probe as _;
 
 // 48 MHz is max.
@@ -50,14 +64,19 @@ pub const CLOCK: u32 = 1_000_000;
 
 pub type Time = clock::Time<{ CLOCK }>;
 
-#[entry]
+/// Seed for the track mutations. There is no source of entropy, so the same
+/// mutations play out after every power up.
+const MUTATE_SEED: u32 = 0x5eed_1234;
+
+#[cfg_attr(not(test), entry)]
+#[cfg_attr(test, allow(dead_code))]
 fn main() -> ! {
     // Ensure constants are sane.
     assert!(TIME_READ_WAIT.subsec_micros() < TIME_OFF);
 
     // 1. HAL init
     // Set up ARM Cortex-M peripherals. These are common to many MCUs, including all STM32 ones.
-    let cp = pac::CorePeripherals::take().unwrap();
+    let mut cp = pac::CorePeripherals::take().unwrap();
     // Set up peripherals specific to the microcontroller you're using.
     let dp = pac::Peripherals::take().unwrap();
 
@@ -122,10 +141,10 @@ fn main() -> ! {
 
     let row5_swl: Row5Swl = gpiof.pf0.into_floating_input();
 
-    let _out_gate1: OutGate1 = gpiob.pb5.into_push_pull_output();
-    let _out_gate2: OutGate2 = gpiob.pb7.into_push_pull_output();
-    let _out_gate3: OutGate3 = gpiob.pb4.into_push_pull_output();
-    let _out_gate4: OutGate4 = gpiob.pb6.into_push_pull_output();
+    let mut out_gate1: OutGate1 = gpiob.pb5.into_push_pull_output();
+    let mut out_gate2: OutGate2 = gpiob.pb7.into_push_pull_output();
+    let mut out_gate3: OutGate3 = gpiob.pb4.into_push_pull_output();
+    let mut out_gate4: OutGate4 = gpiob.pb6.into_push_pull_output();
 
     let led_grid_pins = LedGridPins {
         col1: gpioa.pa12.into_flip_pin(),
@@ -144,10 +163,7 @@ fn main() -> ! {
         row5: gpiof.pf1.into_flip_pin(),
     };
 
-    let mut app_input = AppInput {
-        in_clock: PinDigitalIn(in_clock).edge(),
-        in_reset: PinDigitalIn(in_reset).edge(),
-
+    let app_input = AppInput {
         rot_row1: Encoder::new(QuadSource {
             pin_a: gpiod.pd0.into_floating_input(),
             pin_b: gpioa.pa15.into_floating_input(),
@@ -165,104 +181,105 @@ fn main() -> ! {
         swl_row3: PinDigitalIn(row3_swl).debounce().edge(),
         swl_row4: PinDigitalIn(row4_swl).debounce().edge(),
         swl_row5: PinDigitalIn(row5_swl).debounce().edge(),
-
-        last_clock: None,
     };
 
     let mut led_grid = LedGrid::new(led_grid_pins);
 
-    led_grid.set_leds(0, BiLed::Off, &[BiLed::Off; 8]);
-
-    let stop_watch = dp.TIM3.stopwatch(&mut clocks);
-
-    let mut clock = {
-        let orig = stop_watch.now();
-        let sample_fn = move || stop_watch.elapsed(orig).ticks();
-        Clock::<_, CLOCK>::new_with_bits(12, sample_fn)
-    };
+    led_grid.set_leds(
+        Time::default(),
+        0,
+        BiLed::Off,
+        &[BiLed::Off; 8],
+        &[LEVEL_MAX; 8],
+        0,
+    );
+
+    // The stopwatch keeps TIM3 running, the clock samples its counter directly so
+    // that the clock can be shared with the scan interrupt. Prescaled to 1 MHz, so
+    // the counter is in µs and wraps every 2^16 µs.
+    let mut stop_watch = dp.TIM3.stopwatch(&mut clocks);
+    stop_watch.set_prescaler((clocks.clocks.apb_tim_clk.0 / CLOCK - 1) as u16);
+    // NOTE(unsafe) the prescaler is only loaded on an update event, generate one now.
+    unsafe { (*pac::TIM3::ptr()).egr.write(|w| w.ug().set_bit()) };
+
+    let clock = AppClock::new_with_bits(16, sample_tim3 as fn() -> u32);
+
+    let scanner = Scanner::new(
+        led_grid,
+        app_input,
+        dp.TIM14.timer(&mut clocks),
+        (clocks.clocks.apb_tim_clk.0 / CLOCK - 1) as u16,
+    );
+    scanner.start(
+        Shared {
+            clock,
+            oper_queue: OperQueue::new(),
+            leds: Default::default(),
+            levels: [[LEVEL_MAX; 8]; 5],
+        },
+        &mut cp.NVIC,
+    );
+
+    Capture::start(in_clock, in_reset, dp.EXTI);
 
     // App state stuff
 
-    let mut start = clock.now();
+    let mut start = scan::now();
     let mut loop_count = 0_u64;
+    let mut loop_max = Time::default();
 
-    let mut run_step_idx = 0;
-    let mut run_step_time = GRID_STEPS[run_step_idx].time();
-    let mut run_step_start = clock.now();
-    let mut run_col = Col(0);
-    let mut run_do_read = false;
-
-    let mut app_state = AppState::new();
-    let mut oper_queue = OperQueue::new();
+    let mut app_state = AppState::new(MUTATE_SEED);
+    let mut clock_gen = ClockGen::default();
 
     info!("Starting…");
 
     loop {
-        clock.tick();
-        let now = clock.now();
-
-        {
-            // Each RunStep has a time of how long we're going to "dwell",
-            // on that step. This lapsed time is how long we have stayed
-            // on the current step.
-            let run_time_lapsed = now - run_step_start;
-
-            if run_time_lapsed >= run_step_time {
-                // Advance the RunStep forward in the GRID_STEPS, reset timers etc.
-                run_step_idx += 1;
-                run_step_idx %= GRID_STEPS.len();
-                let run_step = &GRID_STEPS[run_step_idx];
-                run_step_time = run_step.time();
-                run_step_start = now;
-
-                // The GridStep tells us what we are to do. Turning on LEDs,
-                // and which to turn on, or read input (GridStep::Off) for
-                // some certain column.
-                match run_step {
-                    GridStep::Led(_, on, row) => {
-                        let leds = app_state.led_row(row.0);
-                        led_grid.set_leds(row.0, *on, leds);
-                    }
-                    GridStep::Off(_, col) => {
-                        led_grid.set_col(col.0);
-                        run_col = *col;
-                        run_do_read = true;
-                    }
-                }
-            }
+        let now = scan::now();
 
-            // TIME_READ_WAIT is a delay to let pin outputs settle between
-            // setting the column to high and actually doing the read.
-            if run_do_read && run_time_lapsed > TIME_READ_WAIT {
-                run_do_read = false;
-
-                // Read input and push update into app state.
-                app_input.read_input(now, run_col, &mut oper_queue);
+        while let Some(oper) = scan::pop_oper() {
+            if let Some(oper) = clock_gen.filter(now, oper, app_state.settings()) {
+                app_state.apply_oper(now, oper);
             }
         }
 
-        let len = oper_queue.len();
-        if len > 0 {
-            while let Some(oper) = oper_queue.pop() {
-                app_state.apply_oper(now, oper);
-            }
+        if let Some(oper) = clock_gen.tick(now, app_state.settings()) {
+            app_state.apply_oper(now, oper);
         }
 
         // After we applied operations.
         app_state.tick(now);
 
+        scan::publish_leds(app_state.leds(), app_state.levels());
+
+        {
+            let gates = app_state.gates();
+            set_gate(&mut out_gate1, gates[0]);
+            set_gate(&mut out_gate2, gates[1]);
+            set_gate(&mut out_gate3, gates[2]);
+            set_gate(&mut out_gate4, gates[3]);
+        }
+
         {
             const REPORT_MILLIS: i64 = 1500;
 
+            // The worst loop is the headroom we have for sequencing. Any time
+            // spent in the scan interrupt is included.
+            let loop_time = scan::now() - now;
+            if loop_time > loop_max {
+                loop_max = loop_time;
+            }
+
             let time_lapsed = now - start;
             if time_lapsed > Time::from_millis(REPORT_MILLIS) {
                 info!(
-                    "{} {}µS/loop",
+                    "{} {}µS/loop, worst {}",
                     now,
                     (1000 * REPORT_MILLIS) as f32 / loop_count as f32,
+                    loop_max,
                 );
                 start = now;
                 loop_count = 0;
+                loop_max = Time::default();
             }
             loop_count += 1;
         }
@@ -271,11 +288,26 @@ fn main() -> ! {
 
 // same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
+#[cfg(not(test))]
 #[defmt::panic_handler]
 fn panic() -> ! {
     cortex_m::asm::udf()
 }
 
+/// Counter of the stopwatch timer, in µs.
+fn sample_tim3() -> u32 {
+    // NOTE(unsafe) read only access to the counter of the stopwatch timer.
+    unsafe { (*pac::TIM3::ptr()).cnt.read().bits() }
+}
+
+fn set_gate<P: OutputPin>(pin: &mut P, on: bool) {
+    if on {
+        pin.set_high().ok();
+    } else {
+        pin.set_low().ok();
+    }
+}
+
 /// Time to keep red LEDs on.
 const TIME_RED: i64 = 400;
 /// Time to keep green LEDs on.
@@ -287,6 +319,23 @@ const TIME_OFF: i64 = 400;
 /// be less than TIME_OFF.
 const TIME_READ_WAIT: Time = Time::from_micros(50);
 
+/// Micros of each brightness sub-slot in a LED step.
+pub type SubSlots = [u16; LEVEL_MAX as usize];
+
+/// Divides a LED step of `time` micros into its sub-slots. Done at compile time,
+/// so the scan interrupt only looks up the wait.
+const fn sub_slots(time: i64) -> SubSlots {
+    let mut slots = [0; LEVEL_MAX as usize];
+    let mut sub = 0;
+    while sub < slots.len() {
+        let start = time * sub as i64 / LEVEL_MAX as i64;
+        let end = time * (sub as i64 + 1) / LEVEL_MAX as i64;
+        slots[sub] = (end - start) as u16;
+        sub += 1;
+    }
+    slots
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub struct Row(usize);
 #[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
@@ -309,42 +358,32 @@ impl Deref for Col {
 }
 
 pub enum GridStep {
-    /// Light a row of LEDs
-    Led(i64, BiLed, Row),
+    /// Light a row of LEDs, for the micros of each sub-slot.
+    Led(SubSlots, BiLed, Row),
     /// Turn of all LEDs and read input for a column.
     Off(i64, Col),
 }
 
-impl GridStep {
-    pub fn time(&self) -> Time {
-        let v = match self {
-            GridStep::Led(v, _, _) => v,
-            GridStep::Off(v, _) => v,
-        };
-        Time::from_micros(*v)
-    }
-}
-
 static GRID_STEPS: &[GridStep] = &[
-    GridStep::Led(TIME_RED, BiLed::Red, Row(0)),
+    GridStep::Led(sub_slots(TIME_RED), BiLed::Red, Row(0)),
     GridStep::Off(TIME_OFF, Col(0)),
-    GridStep::Led(TIME_GRN, BiLed::Grn, Row(0)),
+    GridStep::Led(sub_slots(TIME_GRN), BiLed::Grn, Row(0)),
     GridStep::Off(TIME_OFF, Col(1)),
-    GridStep::Led(TIME_RED, BiLed::Red, Row(1)),
+    GridStep::Led(sub_slots(TIME_RED), BiLed::Red, Row(1)),
     GridStep::Off(TIME_OFF, Col(2)),
-    GridStep::Led(TIME_GRN, BiLed::Grn, Row(1)),
+    GridStep::Led(sub_slots(TIME_GRN), BiLed::Grn, Row(1)),
     GridStep::Off(TIME_OFF, Col(3)),
-    GridStep::Led(TIME_RED, BiLed::Red, Row(2)),
+    GridStep::Led(sub_slots(TIME_RED), BiLed::Red, Row(2)),
     GridStep::Off(TIME_OFF, Col(4)),
-    GridStep::Led(TIME_RED, BiLed::Red, Row(2)),
+    GridStep::Led(sub_slots(TIME_GRN), BiLed::Grn, Row(2)),
     GridStep::Off(TIME_OFF, Col(5)),
-    GridStep::Led(TIME_RED, BiLed::Red, Row(3)),
+    GridStep::Led(sub_slots(TIME_RED), BiLed::Red, Row(3)),
     GridStep::Off(TIME_OFF, Col(6)),
-    GridStep::Led(TIME_RED, BiLed::Red, Row(3)),
+    GridStep::Led(sub_slots(TIME_GRN), BiLed::Grn, Row(3)),
     GridStep::Off(TIME_OFF, Col(7)),
-    GridStep::Led(TIME_RED, BiLed::Red, Row(4)),
+    GridStep::Led(sub_slots(TIME_RED), BiLed::Red, Row(4)),
     GridStep::Off(TIME_OFF, Col(0)),
-    GridStep::Led(TIME_RED, BiLed::Red, Row(4)),
+    GridStep::Led(sub_slots(TIME_GRN), BiLed::Grn, Row(4)),
     GridStep::Off(TIME_OFF, Col(1)),
 ];
 
@@ -396,4 +435,5 @@ pub type OutGate2 = gpiob::PB7<Output<PushPull>>;
 pub type OutGate3 = gpiob::PB4<Output<PushPull>>;
 pub type OutGate4 = gpiob::PB6<Output<PushPull>>;
 
-pub type OperQueue = RingBuf<Oper, 64>;
+/// Drained by the main loop every pass, so it only needs to cover a slow render.
+pub type OperQueue = RingBuf<Oper, 32>;
@@ -22,9 +22,21 @@ pub enum MachineState {
     /// The u32 tells us which one.
     HoldLower(u32),
 
+    /// Copy button is down.
+    Copy,
+
+    /// Clear button is down.
+    Clear,
+
+    /// Both copy and clear are down. Fill is active.
+    Fill,
+
     /// Both shift and velocity is pressed.
     Reset,
 
+    /// Both shift and clear are down. The encoders edit the euclidean generator.
+    Euclid,
+
     /// Wait for buttons to clear so we can go back to normal.
     WaitForClear,
 }
@@ -35,22 +47,31 @@ impl MachineState {
             MachineState::Normal => match (
                 buttons.is_shift(),
                 buttons.is_vel(),
+                buttons.is_copy(),
+                buttons.is_clear(),
                 buttons.is_rotary_upper(),
                 buttons.is_rotary_lower(),
             ) {
-                (true, true, None, None) => *self = MachineState::Reset,
-                (true, false, None, None) => *self = MachineState::Shift,
-                (false, true, None, None) => *self = MachineState::Velocity,
-                (false, false, Some(b), None) => *self = MachineState::HoldUpper(b),
-                (false, false, None, Some(b)) => *self = MachineState::HoldLower(b),
+                (true, true, false, false, None, None) => *self = MachineState::Reset,
+                (true, false, false, false, None, None) => *self = MachineState::Shift,
+                (false, true, false, false, None, None) => *self = MachineState::Velocity,
+                (false, false, true, false, None, None) => *self = MachineState::Copy,
+                (false, false, false, true, None, None) => *self = MachineState::Clear,
+                (false, false, true, true, None, None) => *self = MachineState::Fill,
+                (true, false, false, true, None, None) => *self = MachineState::Euclid,
+                (false, false, false, false, Some(b), None) => *self = MachineState::HoldUpper(b),
+                (false, false, false, false, None, Some(b)) => *self = MachineState::HoldLower(b),
                 _ => {}
             },
 
-            MachineState::Shift => match (buttons.is_shift(), buttons.is_vel()) {
-                (true, false) => {} // stay in state
-                (true, true) => *self = MachineState::Reset,
-                _ => *self = MachineState::WaitForClear,
-            },
+            MachineState::Shift => {
+                match (buttons.is_shift(), buttons.is_vel(), buttons.is_clear()) {
+                    (true, false, false) => {} // stay in state
+                    (true, true, _) => *self = MachineState::Reset,
+                    (true, false, true) => *self = MachineState::Euclid,
+                    _ => *self = MachineState::WaitForClear,
+                }
+            }
 
             MachineState::Velocity => match (buttons.is_shift(), buttons.is_vel()) {
                 (false, true) => {} //stay in state
@@ -68,6 +89,27 @@ impl MachineState {
                 _ => *self = MachineState::WaitForClear,
             },
 
+            MachineState::Copy => match (buttons.is_copy(), buttons.is_clear()) {
+                (true, false) => {} // stay in state
+                (true, true) => *self = MachineState::Fill,
+                _ => *self = MachineState::WaitForClear,
+            },
+
+            MachineState::Clear => {
+                match (buttons.is_shift(), buttons.is_copy(), buttons.is_clear()) {
+                    (false, false, true) => {} // stay in state
+                    (false, true, true) => *self = MachineState::Fill,
+                    (true, false, true) => *self = MachineState::Euclid,
+                    _ => *self = MachineState::WaitForClear,
+                }
+            }
+
+            MachineState::Fill => {
+                if !buttons.is_copy() || !buttons.is_clear() {
+                    *self = MachineState::WaitForClear;
+                }
+            }
+
             MachineState::Reset => match (buttons.is_shift(), buttons.is_vel()) {
                 (true, true) => {} // stay in state
                 (true, false) => *self = MachineState::Shift,
@@ -75,8 +117,14 @@ impl MachineState {
                 (false, false) => *self = MachineState::WaitForClear,
             },
 
+            MachineState::Euclid => {
+                if !buttons.is_shift() || !buttons.is_clear() {
+                    *self = MachineState::WaitForClear;
+                }
+            }
+
             MachineState::WaitForClear => {
-                if buttons.is_clear() {
+                if buttons.is_released() {
                     *self = MachineState::Normal;
                 }
             }
@@ -0,0 +1,30 @@
+/// Small xorshift PRNG. Deterministic for a given seed.
+#[derive(Clone, Copy)]
+pub struct Rng(u32);
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        // Xorshift gets stuck on 0.
+        Rng(seed.max(1))
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    /// Random number in 0..n
+    pub fn below(&mut self, n: u32) -> u32 {
+        self.next_u32() % n
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Rng::new(0x2545_f491)
+    }
+}
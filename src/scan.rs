@@ -0,0 +1,230 @@
+use core::cell::RefCell;
+
+use alg::clock::Clock;
+use alg::ring_buf::RingBuf;
+use cortex_m::interrupt::{free, CriticalSection, Mutex};
+
+use crate::hal::prelude::*;
+use crate::hal::stm32::{self as pac, interrupt};
+use crate::hal::timer::Timer;
+use crate::input::AppInput;
+use crate::led_grid::{BiLed, LedGrid, Level, LEVEL_MAX};
+use crate::state::Oper;
+use crate::{GridStep, OperQueue, Time, CLOCK, GRID_STEPS, TIME_READ_WAIT};
+
+pub type AppClock = Clock<fn() -> u32, { CLOCK }>;
+
+/// Operations read in one input scan, at most one per input in a column.
+type ScanQueue = RingBuf<Oper, 16>;
+
+/// The scan runs below the clock capture, so edges are timestamped also while
+/// scanning. Only the top two bits are implemented on Cortex-M0+.
+const SCAN_PRIORITY: u8 = 0x40;
+
+/// State shared between the main loop and the scan interrupt.
+pub struct Shared {
+    pub clock: AppClock,
+
+    /// Operations read from input, to be applied by the main loop.
+    pub oper_queue: OperQueue,
+
+    /// The LEDs to show, as last published by the main loop.
+    pub leds: [[BiLed; 8]; 5],
+    pub levels: [[Level; 8]; 5],
+}
+
+impl Shared {
+    /// Tick the clock and get the current time.
+    pub fn now(&mut self) -> Time {
+        self.clock.tick();
+        self.clock.now()
+    }
+}
+
+static SHARED: Mutex<RefCell<Option<Shared>>> = Mutex::new(RefCell::new(None));
+
+/// Hands the scanner over to the interrupt, which takes it on the first run.
+static SCANNER: Mutex<RefCell<Option<Scanner>>> = Mutex::new(RefCell::new(None));
+
+/// Steps through GRID_STEPS from a timer interrupt. Each interrupt handles one
+/// event in the current GridStep and sets the timer for the next one.
+pub struct Scanner {
+    led_grid: LedGrid,
+    input: AppInput,
+    timer: Timer<pac::TIM14>,
+
+    /// Index into GRID_STEPS.
+    step_idx: usize,
+
+    /// Timer prescaler for counting in µs.
+    prescaler: u16,
+
+    /// Event within the step. Sub-slot for GridStep::Led, 0 set column and 1
+    /// read input for GridStep::Off.
+    event: Level,
+
+    /// The row being lit and the time, as read on its first sub-slot. The other
+    /// sub-slots only turn LEDs off, without locking the shared state.
+    row_now: Time,
+    row_leds: [BiLed; 8],
+    row_levels: [Level; 8],
+}
+
+impl Scanner {
+    pub fn new(
+        led_grid: LedGrid,
+        input: AppInput,
+        timer: Timer<pac::TIM14>,
+        prescaler: u16,
+    ) -> Self {
+        Scanner {
+            led_grid,
+            input,
+            timer,
+            prescaler,
+            step_idx: 0,
+            event: 0,
+            row_now: Time::default(),
+            row_leds: [BiLed::Off; 8],
+            row_levels: [LEVEL_MAX; 8],
+        }
+    }
+
+    /// Hand over the scanner and shared state to the interrupt and start scanning.
+    pub fn start(mut self, shared: Shared, nvic: &mut pac::NVIC) {
+        self.timer.start(10.micros());
+
+        // Count in µs, so that each event only has to reload the timer.
+        // NOTE(unsafe) the scanner owns TIM14. The prescaler is only loaded on an
+        // update event, generate one now.
+        unsafe {
+            let tim = &*pac::TIM14::ptr();
+            tim.psc.write(|w| w.bits(self.prescaler as u32));
+            tim.egr.write(|w| w.ug().set_bit());
+        }
+        self.timer.clear_irq();
+        self.timer.listen();
+
+        free(|cs| {
+            SHARED.borrow(cs).replace(Some(shared));
+            SCANNER.borrow(cs).replace(Some(self));
+        });
+
+        // NOTE(unsafe) the shared state is set up before the interrupt is unmasked.
+        unsafe {
+            nvic.set_priority(pac::Interrupt::TIM14, SCAN_PRIORITY);
+            pac::NVIC::unmask(pac::Interrupt::TIM14);
+        }
+    }
+
+    /// One scan event. The shared state is only locked to copy in and out, so
+    /// the clock capture isn't held up by the scan.
+    fn run(&mut self) {
+        self.timer.clear_irq();
+
+        let step = &GRID_STEPS[self.step_idx];
+
+        // Micros until the next event.
+        let wait = match step {
+            GridStep::Led(slots, on, row) => {
+                let sub = self.event;
+                if sub == 0 {
+                    let now = now();
+                    let (leds, levels) = free(|cs| {
+                        with_shared(cs, |shared| (shared.leds[row.0], shared.levels[row.0]))
+                    })
+                    .expect("shared state");
+                    self.row_now = now;
+                    self.row_leds = leds;
+                    self.row_levels = levels;
+                }
+                self.led_grid.set_leds(
+                    self.row_now,
+                    row.0,
+                    *on,
+                    &self.row_leds,
+                    &self.row_levels,
+                    sub,
+                );
+
+                // Dim LEDs are turned off after their share of the dwell.
+                let wait = slots[sub as usize];
+                if sub + 1 < LEVEL_MAX {
+                    self.event += 1;
+                } else {
+                    self.next_step();
+                }
+                wait
+            }
+
+            GridStep::Off(time, col) => {
+                let read_wait = TIME_READ_WAIT.subsec_micros();
+                if self.event == 0 {
+                    self.led_grid.set_col(col.0);
+                    // Let pin outputs settle before reading.
+                    self.event = 1;
+                    read_wait as u16
+                } else {
+                    let mut opers = ScanQueue::new();
+                    self.input.read_input(now(), *col, &mut opers);
+                    free(|cs| {
+                        with_shared(cs, |shared| {
+                            while let Some(oper) = opers.pop() {
+                                shared.oper_queue.push(oper);
+                            }
+                        })
+                    });
+                    self.next_step();
+                    (time - read_wait) as u16
+                }
+            }
+        };
+
+        // NOTE(unsafe) the scanner owns TIM14. The counter restarts for the wait,
+        // the prescaler is left as set up in start.
+        unsafe {
+            let tim = &*pac::TIM14::ptr();
+            tim.cnt.reset();
+            tim.arr.write(|w| w.bits(wait as u32));
+        }
+    }
+
+    fn next_step(&mut self) {
+        self.step_idx = (self.step_idx + 1) % GRID_STEPS.len();
+        self.event = 0;
+    }
+}
+
+#[interrupt]
+fn TIM14() {
+    static mut SCANNER_OWNED: Option<Scanner> = None;
+
+    let scanner =
+        SCANNER_OWNED.get_or_insert_with(|| free(|cs| SCANNER.borrow(cs).take()).expect("scanner"));
+    scanner.run();
+}
+
+/// Access the shared state from another interrupt.
+pub fn with_shared<R>(cs: &CriticalSection, f: impl FnOnce(&mut Shared) -> R) -> Option<R> {
+    SHARED.borrow(cs).borrow_mut().as_mut().map(f)
+}
+
+/// Tick the shared clock and get the current time.
+pub fn now() -> Time {
+    free(|cs| with_shared(cs, |shared| shared.now()).expect("shared state"))
+}
+
+/// Take the next operation read by the scan interrupt.
+pub fn pop_oper() -> Option<Oper> {
+    free(|cs| SHARED.borrow(cs).borrow_mut().as_mut()?.oper_queue.pop())
+}
+
+/// Publish the LEDs to show.
+pub fn publish_leds(leds: &[[BiLed; 8]; 5], levels: &[[Level; 8]; 5]) {
+    free(|cs| {
+        if let Some(shared) = SHARED.borrow(cs).borrow_mut().as_mut() {
+            shared.leds = *leds;
+            shared.levels = *levels;
+        }
+    });
+}
@@ -0,0 +1,183 @@
+use crate::val::Val;
+
+/// Global settings. These are not part of a bank.
+pub struct Settings {
+    /// LED brightness. Defaults to 4.
+    ///
+    /// * Range is 1 - 4.
+    pub brightness: Val<1, 4>,
+
+    /// Where the clock comes from. Defaults to auto.
+    pub clock_source: ClockSource,
+
+    /// Tempo of the internal clock in beats per minute. Defaults to 120.
+    ///
+    /// * Range is 20 - 300.
+    pub bpm: u16,
+
+    /// Resolution of the clock input. Defaults to 4 PPQN.
+    pub ppqn: Ppqn,
+
+    /// Steps per beat (quarter note). Defaults to 4, i.e. 16th notes.
+    pub steps_per_beat: StepsPerBeat,
+
+    /// What the reset input does. Defaults to reset on next clock.
+    pub reset_mode: ResetMode,
+}
+
+/// Function of the reset input.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum ResetMode {
+    /// The next clock goes to step 0.
+    #[default]
+    NextClock,
+    /// Go to step 0 right away, re-triggering it. The next clock goes to step 1.
+    Immediate,
+    /// The next clock on a beat goes to step 0.
+    Beat,
+    /// Run while the input is high, stop while low. Starts from step 0.
+    RunStop,
+    /// Go to the next pattern. Does nothing until there are pattern banks.
+    NextPattern,
+    /// Not a reset, the Y clock of cartesian tracks.
+    YClock,
+}
+
+/// Pulses per quarter note of the clock input.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum Ppqn {
+    P1,
+    P2,
+    #[default]
+    P4,
+    P8,
+    P24,
+    P48,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum StepsPerBeat {
+    S1,
+    S2,
+    #[default]
+    S4,
+    S8,
+}
+
+/// Where clock pulses come from.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    /// External clock while it's patched, internal when no external clock arrives.
+    #[default]
+    Auto,
+    /// Always internal, the clock input is ignored.
+    Internal,
+    /// Always external.
+    External,
+}
+
+impl Settings {
+    pub fn add_bpm(&mut self, v: i8) {
+        self.bpm = (self.bpm as i16).saturating_add(v as i16).clamp(20, 300) as u16;
+    }
+
+    /// Interval between internal clock pulses. One pulse per step.
+    pub fn clock_interval_micros(&self) -> i64 {
+        60_000_000 / (self.bpm as i64 * self.steps_per_beat.steps() as i64)
+    }
+
+    /// Clock input pulses per step.
+    ///
+    /// When the clock input has fewer pulses per beat than steps per beat, each
+    /// pulse is several steps, see steps_per_pulse().
+    pub fn pulses_per_step(&self) -> u8 {
+        (self.ppqn.pulses() / self.steps_per_beat.steps()).max(1)
+    }
+
+    /// Steps per clock input pulse. More than 1 when the clock input has fewer
+    /// pulses per beat than steps per beat.
+    pub fn steps_per_pulse(&self) -> u8 {
+        (self.steps_per_beat.steps() / self.ppqn.pulses()).max(1)
+    }
+}
+
+impl Ppqn {
+    pub fn pulses(&self) -> u8 {
+        match self {
+            Ppqn::P1 => 1,
+            Ppqn::P2 => 2,
+            Ppqn::P4 => 4,
+            Ppqn::P8 => 8,
+            Ppqn::P24 => 24,
+            Ppqn::P48 => 48,
+        }
+    }
+
+    pub fn add(&mut self, v: i8) {
+        use Ppqn::*;
+        *self = match (*self as i8).saturating_add(v).clamp(0, 5) {
+            0 => P1,
+            1 => P2,
+            2 => P4,
+            3 => P8,
+            4 => P24,
+            _ => P48,
+        };
+    }
+}
+
+impl ResetMode {
+    /// Cycle through the modes.
+    pub fn add(&mut self, v: i8) {
+        use ResetMode::*;
+        *self = match (*self as i16 + v as i16).rem_euclid(6) {
+            0 => NextClock,
+            1 => Immediate,
+            2 => Beat,
+            3 => RunStop,
+            4 => NextPattern,
+            _ => YClock,
+        };
+    }
+}
+
+impl StepsPerBeat {
+    pub fn steps(&self) -> u8 {
+        1 << *self as u8
+    }
+
+    pub fn add(&mut self, v: i8) {
+        use StepsPerBeat::*;
+        *self = match (*self as i8).saturating_add(v).clamp(0, 3) {
+            0 => S1,
+            1 => S2,
+            2 => S4,
+            _ => S8,
+        };
+    }
+}
+
+impl ClockSource {
+    /// Cycle through the sources.
+    pub fn add(&mut self, v: i8) {
+        use ClockSource::*;
+        *self = match (*self as i16 + v as i16).rem_euclid(3) {
+            0 => Auto,
+            1 => Internal,
+            _ => External,
+        };
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            brightness: Val(4),
+            clock_source: ClockSource::default(),
+            bpm: 120,
+            ppqn: Ppqn::default(),
+            steps_per_beat: StepsPerBeat::default(),
+            reset_mode: ResetMode::default(),
+        }
+    }
+}
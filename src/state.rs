@@ -2,24 +2,61 @@ use alg::bitfield::Bitfield;
 use alg::tempo::Tempo;
 
 use crate::buttons::Buttons;
-use crate::led_grid::BiLed;
+use crate::clipboard::{Clip, Clipboard};
+use crate::led_grid::{BiLed, LedColor, Level, Rate, LEVEL_MAX};
 use crate::mstate::MachineState;
 use crate::music::{Scale, Tone};
-use crate::track::{Track, TrackStep, TrackSync};
+use crate::rng::Rng;
+use crate::settings::{ResetMode, Settings};
+use crate::track::{Euclid, Track, TrackDirection, TrackStep, TrackSync};
+use crate::transform::Transform;
 use crate::val::Val;
+use crate::view::{AsView, ValueView, VIEW_MILLIS};
 use crate::{Col, Row, Time, CLOCK};
 
 pub const TRACK_COUNT: usize = 4;
 
+/// Time to flash the LEDs on an error, such as a rejected paste.
+const ERROR_FLASH_MILLIS: i64 = 300;
+
+/// Taps further apart than this start a new tap tempo.
+const TAP_TIMEOUT_MILLIS: i64 = 2000;
+
+/// Time to show the beat on the shift LED after the last tap.
+const TAP_SHOW_MILLIS: i64 = 5000;
+
+/// Time the shift LED is lit on each beat.
+const BEAT_FLASH_MILLIS: i64 = 60;
+
+/// Holding a part button this long selects part 5-8 instead of 1-4.
+const PART_LONG_PRESS_MILLIS: i64 = 500;
+
+/// Clock is lost when there is no clock for this many predicted intervals.
+pub(crate) const CLOCK_LOSS_FACTOR: u64 = 4;
+
+/// Time to hold for a bank reset.
+const BANK_RESET_MILLIS: i64 = 2000;
+
+/// Time to hold for a factory reset.
+const FACTORY_RESET_MILLIS: i64 = 4000;
+
 #[derive(Copy, Clone, defmt::Format)]
 /// The operations that can be done on the state.
 pub enum Oper {
-    /// Clock pulse. The time is the interval from the previous clock pulse.
+    /// Clock pulse. The time is the interval from the previous clock pulse, or
+    /// zero when it isn't known, like after a gap in the clock.
     Clock(Time),
 
-    /// Reset.
+    /// External clock pulse that isn't a step. The time is the pulse interval
+    /// scaled to a step, and only feeds the tempo detection.
+    Tempo(Time),
+
+    /// Reset input going high.
     Reset,
 
+    /// Reset input going low. Only matters when the reset input is a run/stop gate.
+    ResetEnd,
+
     /// Input from a rotary encoder.
     RotaryEncoder(Row, Col, i8),
 
@@ -37,13 +74,38 @@ pub struct AppState {
     /// If next tick is going to reset back to 0.
     next_is_reset: bool,
 
+    /// If the next tick on a beat is going to reset back to 0.
+    next_beat_is_reset: bool,
+
     /// Beat detection/tempo
     tempo: Tempo<{ CLOCK }>,
 
     /// Interval to next predicted clock.
     predicted: Time,
 
-    /// Ever increasing count of the clock. Never resets.
+    /// Time of the last clock.
+    last_clock: Time,
+
+    /// No clock for a while. Gates are closed until the next clock.
+    clock_lost: bool,
+
+    /// Sum of the tap intervals in the current tap tempo.
+    tap_sum: Time,
+
+    /// Time of the last tap.
+    last_tap: Option<Time>,
+
+    /// Number of taps in the current tap tempo.
+    taps: u8,
+
+    /// Count of step clocks, also when paused. Used for the beat. Wraps, which
+    /// is fine since steps per beat divides 256.
+    pulse_count: u8,
+
+    /// Time of the last beat.
+    last_beat: Time,
+
+    /// Ever increasing count of the clock while playing. Never resets.
     clock_count: u64,
 
     /// Current global playhead. Goes from 0..whenever external reset comes.
@@ -52,21 +114,96 @@ pub struct AppState {
     /// Parameters for pattern.
     params: PatternParams,
 
+    /// Global settings.
+    settings: Settings,
+
     /// Which tracks are currently selected.
     selected_tracks: TrackSelection,
 
-    /// Currently selected track part. 0-3
+    /// Currently selected track part. 0-7
     selected_part: usize,
 
+    /// Part button being held and since when.
+    part_press: Option<(Col, Time)>,
+
+    /// First step button held for setting a loop window. Button and absolute step.
+    window_first: Option<(Row, Col, usize)>,
+
     /// Tracks with parameters and notes.
     tracks: [Track; TRACK_COUNT],
 
     /// Playhead for each track.
     track_playhead: [usize; TRACK_COUNT],
 
+    /// Last position for each track, before direction is applied.
+    track_pos: [u64; TRACK_COUNT],
+
+    /// Step offset in the play range for tracks in random and drunk direction.
+    random_offset: [usize; TRACK_COUNT],
+
+    /// Random numbers for play direction and probability.
+    rng: Rng,
+
+    /// Random numbers for mutation. Kept apart from rng so that mutations only
+    /// depend on the seed.
+    mutate_rng: Rng,
+
+    /// Loops played by each track since reset, for conditional trigs.
+    iteration: [u32; TRACK_COUNT],
+
+    /// Position within the loop of each track, to tell when it wraps. None after
+    /// reset, so the first step isn't counted as a wrap.
+    loop_pos: [Option<u8>; TRACK_COUNT],
+
+    /// Outcome of the last conditional trig on each track, for Pre/NotPre.
+    pre: [bool; TRACK_COUNT],
+
+    /// Tracks that moved to a new step that is yet to be triggered.
+    trig_due: [bool; TRACK_COUNT],
+
+    /// The last transform, to undo it.
+    undo: Undo,
+
+    /// Step repeat held down.
+    repeat: Option<Repeat>,
+
+    /// Fill latched until the end of the pattern.
+    fill_latch: bool,
+
+    /// Position of each track at the clock fill started doubling its rate, and the
+    /// clock_count then. Both wrap at 32 bits.
+    fill_start: [Option<(u32, u32)>; TRACK_COUNT],
+
+    /// Position (x, y) for each track in TrackSync::Cartesian.
+    cartesian: [(u8, u8); TRACK_COUNT],
+
+    /// Gate output for each track.
+    gates: [bool; TRACK_COUNT],
+
+    /// Time each gate was opened.
+    gate_start: [Time; TRACK_COUNT],
+
+    /// Track steps per clocks for each track as last played, to time the gates.
+    step_ratio: [(u8, u8); TRACK_COUNT],
+
     /// Button state
     buttons: Buttons,
 
+    /// Copy/paste clipboard.
+    clipboard: Clipboard,
+
+    /// Start of an error flash on the LEDs, if one is showing.
+    error_flash: Option<Time>,
+
+    /// Value being edited, shown on the step LEDs since the time.
+    value_view: Option<(Time, ValueView)>,
+
+    /// Ongoing hold to confirm a reset.
+    reset_hold: Option<ResetHold>,
+
+    /// A reset was done, wait for release before another can start.
+    reset_done: bool,
+
     /// LED states.
     ///
     /// row 0 - step row 1
@@ -75,6 +212,9 @@ pub struct AppState {
     /// row 3 - track (col 4-7)
     /// row 4 - shift, clear, vel (col 4-7)
     leds: [[BiLed; 8]; 5],
+
+    /// LED brightness, same layout as leds.
+    levels: [[Level; 8]; 5],
 }
 
 #[derive(Default)]
@@ -86,6 +226,10 @@ impl TrackSelection {
         self.0.set(track, on);
     }
 
+    fn is_selected(&self, track: u8) -> bool {
+        self.0.is(track)
+    }
+
     fn selected(&self) -> impl Iterator<Item = usize> {
         [self.0.is(0), self.0.is(1), self.0.is(2), self.0.is(3)]
             .into_iter()
@@ -96,30 +240,78 @@ impl TrackSelection {
 }
 
 impl AppState {
-    pub fn new() -> Self {
+    /// New state. The mutations are deterministic for a given seed.
+    pub fn new(mutate_seed: u32) -> Self {
         AppState {
+            trig_due: [true; TRACK_COUNT],
+            mutate_rng: Rng::new(mutate_seed),
             ..Default::default()
         }
     }
 
-    pub fn led_row(&self, row: usize) -> &[BiLed; 8] {
-        &self.leds[row]
+    pub fn leds(&self) -> &[[BiLed; 8]; 5] {
+        &self.leds
+    }
+
+    pub fn levels(&self) -> &[[Level; 8]; 5] {
+        &self.levels
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn gates(&self) -> &[bool; TRACK_COUNT] {
+        &self.gates
     }
 
     pub fn apply_oper(&mut self, now: Time, oper: Oper) {
         match oper {
             Oper::Clock(interval) => {
-                self.predicted = self.tempo.predict(interval);
+                self.last_clock = now;
+
+                if self.clock_lost {
+                    // The interval since the clock was lost would pollute the tempo
+                    // detection.
+                    self.clock_lost = false;
+                    info!("Clock back");
+                } else if interval > Time::default() {
+                    // Tempo detection keeps going also when paused.
+                    self.predicted = self.tempo.predict(interval);
+                }
+
+                if self.pulse_count % self.settings.steps_per_beat.steps() == 0 {
+                    self.last_beat = now;
+
+                    if self.next_beat_is_reset {
+                        self.next_beat_is_reset = false;
+                        self.next_is_reset = true;
+                    }
+                }
+                self.pulse_count = self.pulse_count.wrapping_add(1);
+
+                if !self.params.play {
+                    return;
+                }
+
                 self.clock_count += 1;
 
+                self.advance_cartesian(true);
+
                 if self.next_is_reset {
                     self.next_is_reset = false;
-                    self.playhead = 0;
+                    self.rewind(now);
                 } else {
                     self.playhead += 1;
                 }
 
-                self.update_track_playhead();
+                if self.playhead() == 0 {
+                    // A latched fill lasts until the end of the pattern.
+                    self.fill_latch = false;
+                }
+
+                self.update_track_playhead(now);
+                self.update_gates(now);
 
                 trace!(
                     "Tick playhead: {} tick_count: {}",
@@ -128,18 +320,68 @@ impl AppState {
                 );
             }
 
+            Oper::Tempo(interval) => {
+                if !self.clock_lost && interval > Time::default() {
+                    self.predicted = self.tempo.predict(interval);
+                }
+            }
+
             Oper::Reset => {
+                match self.settings.reset_mode {
+                    ResetMode::NextClock => {
+                        // Whatever tick is coming next, it's going to reset back to 0.
+                        self.next_is_reset = true;
+                    }
+
+                    ResetMode::Immediate => {
+                        // Go to 0 now and trigger that step. The next tick goes to 1.
+                        self.next_is_reset = false;
+                        self.rewind(now);
+                        if self.params.play {
+                            self.last_clock = now;
+                            self.update_track_playhead(now);
+                            // Also tracks that are on step 0 already.
+                            self.trig_due = [true; TRACK_COUNT];
+                            self.update_gates(now);
+                        }
+                    }
+
+                    ResetMode::Beat => {
+                        self.next_beat_is_reset = true;
+                    }
+
+                    ResetMode::RunStop => {
+                        // Start from 0 on the next tick.
+                        self.next_is_reset = true;
+                        self.set_play(true);
+                    }
+
+                    ResetMode::NextPattern => {
+                        // TODO go to the next pattern once there are banks of them.
+                        return;
+                    }
+
+                    ResetMode::YClock => {
+                        // The second clock for cartesian tracks, the others don't see it.
+                        self.advance_cartesian(false);
+                        return;
+                    }
+                }
+
                 // Reset might affect the tempo detection.
                 self.tempo.reset();
 
-                // Whatever tick is coming next, it's going to reset back to 0.
-                self.next_is_reset = true;
-
                 info!("Reset");
             }
 
+            Oper::ResetEnd => {
+                if self.settings.reset_mode == ResetMode::RunStop {
+                    self.set_play(false);
+                }
+            }
+
             Oper::RotaryEncoder(row, col, v) => {
-                self.handle_rotary(row, col, v);
+                self.handle_rotary(now, row, col, v);
             }
 
             Oper::LedButton(row, col, on) => {
@@ -150,167 +392,1573 @@ impl AppState {
                     // 2-4 top button
                     self.buttons.set_top(*row, *col, on)
                 }
+
+                self.handle_button(now, row, col, on);
             }
 
             Oper::RotaryButton(row, col, on) => {
                 self.buttons.set_rotary(*row, *col, on);
+
+                if self.mstate == MachineState::Shift && on {
+                    self.handle_rotary_button_shift(now, row, col);
+                }
+
+                if self.mstate == MachineState::Reset && on {
+                    self.handle_rotary_button_global(now, row, col);
+                }
+
+                if self.mstate == MachineState::Euclid && on {
+                    self.handle_rotary_button_euclid(row, col);
+                }
             }
         }
     }
 
     pub fn tick(&mut self, now: Time) {
         self.mstate.transition(&self.buttons);
-        //
-    }
 
-    /// Current playhead, 0-63 for instance (depends on pattern length).
-    pub fn playhead(&self) -> usize {
-        (self.playhead % self.params.length as u64) as usize
+        self.tick_reset(now);
+
+        self.tick_clock_loss(now);
+
+        if self.params.play && !self.clock_lost {
+            // Multiplied tracks move in between clocks.
+            self.update_track_playhead(now);
+            self.update_gates(now);
+        }
+
+        self.update_leds(now);
     }
 
-    fn update_track_playhead(&mut self) {
-        let parm = &self.params;
-        let plen = parm.length as usize;
-        let playhead = self.playhead();
+    fn update_leds(&mut self, now: Time) {
+        let mut leds = [[BiLed::Off; 8]; 5];
+        let mut levels = [[LEVEL_MAX; 8]; 5];
 
-        for i in 0..TRACK_COUNT {
-            let track = &self.tracks[i];
-            let len = track.params.track_length;
-            self.track_playhead[i] = match track.params.sync {
-                TrackSync::Sync => playhead % plen.min(len as usize),
-                TrackSync::Reset => (self.playhead % len as u64) as usize,
-                TrackSync::Free => (self.clock_count % len as u64) as usize,
+        if let Some(start) = self.error_flash {
+            if now - start < Time::from_millis(ERROR_FLASH_MILLIS) {
+                leds = [[BiLed::Red; 8]; 5];
+            } else {
+                self.error_flash = None;
+            }
+        } else if let Some(hold) = &self.reset_hold {
+            // Progress bar over the 16 step LEDs.
+            let on = match hold.kind {
+                ResetKind::Bank => BiLed::Grn,
+                ResetKind::Factory => BiLed::Red,
             };
+            for i in 0..hold.progress(now) {
+                leds[i / 8][i % 8] = on;
+            }
+        } else {
+            self.render_steps(&mut leds, &mut levels);
+            self.render_selection(&mut leds);
+
+            let mut step_leds_free = true;
+
+            if let Some((start, view)) = self.value_view {
+                if now - start < Time::from_millis(VIEW_MILLIS) {
+                    view.render(&mut leds);
+                    step_leds_free = false;
+                } else {
+                    self.value_view = None;
+                }
+            }
+
+            if step_leds_free && self.mstate == MachineState::Shift {
+                // Play/pause on the last step.
+                leds[1][7] = if self.params.play {
+                    BiLed::Grn
+                } else {
+                    BiLed::Red
+                };
+            }
+
+            if !self.params.play {
+                // Shift LED blinks red while paused.
+                leds[4][4] = BiLed::Blink(LedColor::Red, Rate::Slow);
+            }
+
+            if self.clock_lost {
+                // Track LEDs pulse red when there is no clock.
+                leds[3][4..].fill(BiLed::Pulse(LedColor::Red, Rate::Medium));
+            }
+
+            // Shift LED flashes on the beat after tap tempo.
+            let tap_shown = self
+                .last_tap
+                .map(|t| now - t < Time::from_millis(TAP_SHOW_MILLIS))
+                .unwrap_or(false);
+            if tap_shown && now - self.last_beat < Time::from_millis(BEAT_FLASH_MILLIS) {
+                leds[4][4] = BiLed::Grn;
+            }
+        }
+
+        // Global brightness scales all levels, but never all the way to off.
+        let brightness = *self.settings.brightness as Level;
+        for level in levels.iter_mut().flatten() {
+            *level = (*level * brightness).div_ceil(LEVEL_MAX);
         }
+
+        self.leds = leds;
+        self.levels = levels;
     }
 
-    fn handle_rotary(&mut self, row: Row, col: Col, v: i8) {
-        match self.mstate {
-            MachineState::Normal => self.handle_rotary_normal(row, col, v),
-            MachineState::Shift => self.handle_rotary_shift(row, col, v),
-            MachineState::Velocity => self.handle_rotary_vel(row, col, v),
-            MachineState::HoldUpper(_) if *row == 1 => self.handle_rotary_hold(true, col, v),
-            MachineState::HoldLower(_) if *row == 0 => self.handle_rotary_hold(false, col, v),
-            _ => {}
+    /// Steps of the first selected track in the selected part.
+    ///
+    /// * Red - step on. Dimmed by the step probability.
+    /// * Green - playhead on a step that is off.
+    /// * Amber - playhead on a step that is on.
+    ///
+    /// Steps outside the loop window are at the lowest brightness.
+    fn render_steps(&self, leds: &mut [[BiLed; 8]; 5], levels: &mut [[Level; 8]; 5]) {
+        let Some(i) = self.selected_tracks.selected().next() else {
+            return;
+        };
+        let track = &self.tracks[i];
+        let offset = self.selected_part * 16;
+        let (first, len) = track.params.play_range();
+
+        for n in 0..16 {
+            let step = &track.steps[offset + n];
+            let at_playhead = self.track_playhead[i] == offset + n;
+
+            leds[n / 8][n % 8] = match (track.is_on(offset + n), at_playhead) {
+                (true, true) => BiLed::Amber,
+                (false, true) => BiLed::Grn,
+                (true, false) => BiLed::Red,
+                (false, false) => BiLed::Off,
+            };
+
+            if !(first..(first + len)).contains(&(offset + n)) {
+                levels[n / 8][n % 8] = 1;
+            } else if !at_playhead {
+                let prob = *(track.params.base_probability + step.probability) as u16;
+                let level = (prob * LEVEL_MAX as u16).div_ceil(100) as Level;
+                levels[n / 8][n % 8] = level.max(1);
+            }
         }
     }
 
-    fn handle_rotary_normal(&mut self, row: Row, col: Col, v: i8) {
+    /// Selected part (row 2) and tracks (row 3).
+    fn render_selection(&self, leds: &mut [[BiLed; 8]; 5]) {
+        // Part 1-4 is green, 5-8 red.
+        let part_led = if self.selected_part < 4 {
+            BiLed::Grn
+        } else {
+            BiLed::Red
+        };
+        leds[2][4 + self.selected_part % 4] = part_led;
+
         for i in self.selected_tracks.selected() {
-            let step = step_for(self.selected_part, &mut self.tracks[i], row, col);
-            step.tone.add(v);
+            leds[3][4 + i] = BiLed::Grn;
         }
     }
 
-    fn handle_rotary_shift(&mut self, row: Row, col: Col, v: i8) {
-        if *row == 0 {
-            // Track functions.
-            for i in self.selected_tracks.selected() {
-                let track = &mut self.tracks[i];
-                match *col {
-                    0 => track.params.add_length(v),
-                    1 => track.params.base_velocity.add(v),
-                    2 => track.params.base_probability.add(v),
-                    3 => track.params.base_slew.add(v),
-                    _ => {}
-                }
-            }
-        } else if *row == 1 {
-            // Global/pattern functions
-            match *col {
-                0 => self.params.add_length(v),
-                1 => self.params.swing.add(v),
-                6 => self.params.scale.add(v),
-                7 => self.params.root.add(v),
-                _ => {}
-            }
-        }
+    fn flash_error(&mut self, now: Time) {
+        self.error_flash = Some(now);
     }
 
-    fn handle_rotary_vel(&mut self, row: Row, col: Col, v: i8) {
-        for i in self.selected_tracks.selected() {
-            let step = step_for(self.selected_part, &mut self.tracks[i], row, col);
-            step.velocity.add(v);
+    fn show_value(&mut self, now: Time, view: ValueView) {
+        self.value_view = Some((now, view));
+    }
+
+    fn tick_clock_loss(&mut self, now: Time) {
+        if self.clock_lost || self.predicted == Time::default() {
+            return;
+        }
+
+        if now - self.last_clock > time_mul(self.predicted, CLOCK_LOSS_FACTOR) {
+            self.clock_lost = true;
+            self.gates = [false; TRACK_COUNT];
+            info!("Clock lost");
         }
     }
 
-    fn handle_rotary_hold(&mut self, upper: bool, col: Col, v: i8) {
-        // NB we won't get here if it's a rotary on the same road as the
-        // pushed down one.
-        let row = if upper { Row(1) } else { Row(0) };
-        let Some(s) = self.buttons.rotary_to_step() else {
+    /// In reset mode (shift + velocity), hold clear to reset the bank, or
+    /// clear + copy to do a factory reset. Releasing before the progress bar
+    /// is full aborts.
+    fn tick_reset(&mut self, now: Time) {
+        if self.mstate != MachineState::Reset {
+            self.reset_hold = None;
+            self.reset_done = false;
             return;
+        }
+
+        let kind = match (self.buttons.is_clear(), self.buttons.is_copy()) {
+            (true, false) => Some(ResetKind::Bank),
+            (true, true) => Some(ResetKind::Factory),
+            _ => None,
         };
 
-        for i in self.selected_tracks.selected() {
-            let step = step_for(self.selected_part, &mut self.tracks[i], row, col);
-            match s {
-                0 => step.length.add(v),
-                1 => step.velocity.add(v),
-                2 => step.probability.add(v),
-                3 => step.slew.add(v),
-                // 5 => spread cannot be added on individual track level.
-                6 => step.scale.add(v),
-                7 => step.tone.add(v),
-                _ => {}
+        if self.reset_done {
+            if kind.is_none() {
+                self.reset_done = false;
+            }
+            return;
+        }
+
+        let Some(kind) = kind else {
+            self.reset_hold = None;
+            return;
+        };
+
+        match self.reset_hold {
+            Some(hold) if hold.kind == kind => {
+                if hold.progress(now) < 16 {
+                    return;
+                }
+
+                match kind {
+                    ResetKind::Bank => self.bank_reset(),
+                    ResetKind::Factory => self.factory_reset(),
+                }
+
+                self.reset_hold = None;
+                self.reset_done = true;
             }
+            _ => self.reset_hold = Some(ResetHold { kind, start: now }),
         }
     }
-}
 
-fn step_for(selected_part: usize, track: &mut Track, row: Row, col: Col) -> &mut TrackStep {
-    // 16 steps per part
-    let offset_part = selected_part * 16;
-    &mut track.steps[offset_part + *row * 16 + *col]
-}
+    /// Blank the tracks and pattern params of the current bank.
+    fn bank_reset(&mut self) {
+        // In place, all tracks at once would not fit on the stack.
+        for track in &mut self.tracks {
+            track.clear();
+        }
+        self.params = PatternParams::default();
+        self.drop_undo();
 
-struct PatternParams {
-    /// Length of entire pattern. 1-128
-    pub length: usize,
+        info!("Bank reset");
+    }
 
-    /// Percentage swing. Default 50.
-    ///
-    /// 50% is perfectly straight < 50 note is early > 50 note is late.
-    pub swing: Val<1, 99>,
+    /// Blank all the things.
+    fn factory_reset(&mut self) {
+        self.bank_reset();
 
-    /// How the pattern is played. Default is forward.
-    pub direction: PlayDirection,
+        self.selected_tracks = TrackSelection::default();
+        self.selected_part = 0;
+        self.clipboard = Clipboard::default();
+        self.settings = Settings::default();
 
-    /// Whether we are playing or paused right now. Defaults to true.
-    pub play: bool,
+        info!("Factory reset");
+    }
 
-    /// The root key.
-    pub root: Tone,
+    /// Current playhead, 0-63 for instance (depends on pattern length).
+    pub fn playhead(&self) -> usize {
+        (self.playhead % self.params.length as u64) as usize
+    }
 
-    /// The default scale for the pattern. Can be overridden by step.
-    pub scale: Scale,
-}
+    fn update_track_playhead(&mut self, now: Time) {
+        let playhead = self.playhead() as u64;
+        let since_clock = now - self.last_clock;
 
-impl PatternParams {
-    pub fn add_length(&mut self, v: i8) {
-        self.length = (self.length as isize)
-            .saturating_add(v as isize)
-            .clamp(1, 128) as usize;
+        for i in 0..TRACK_COUNT {
+            let params = self.tracks[i].params;
+            let (first, len) = params.play_range();
+
+            // Clocks counted from where the track restarts. Since the pattern playhead
+            // and the playhead both go to 0 on reset, the tracks stay phase aligned.
+            let clocks = match params.sync {
+                TrackSync::Sync => playhead,
+                TrackSync::Reset => self.playhead,
+                TrackSync::Free => self.clock_count,
+                TrackSync::Cartesian => {
+                    // Not clocked, the position is moved by advance_cartesian.
+                    self.step_ratio[i] = (1, 1);
+                    let (x, y) = self.cartesian[i];
+                    self.track_playhead[i] =
+                        params.cartesian_part * 16 + y as usize * 8 + x as usize;
+                    continue;
+                }
+            };
+
+            let doubled = self.params.fill_double
+                && self.is_fill()
+                && self.selected_tracks.is_selected(i as u8);
+
+            let (steps, per) = params.clock_ratio.ratio();
+            let rate = if doubled { steps * 2 } else { steps };
+            self.step_ratio[i] = (rate as u8, per as u8);
+
+            // For multiplied tracks, how many of the in-between steps of the current
+            // clock that have passed, using the predicted interval to the next clock.
+            let sub = if self.predicted > Time::default() {
+                (1..rate)
+                    .take_while(|k| time_mul(since_clock, rate) >= time_mul(self.predicted, *k))
+                    .count() as u64
+            } else {
+                0
+            };
+
+            let pos = if doubled {
+                // Go on from where the track was at the clock fill started, counting
+                // the clocks since then at the double rate. Back to normal, the track
+                // is where it would have been without fill.
+                let (start, start_clock) = *self.fill_start[i]
+                    .get_or_insert((((clocks * steps) / per) as u32, self.clock_count as u32));
+                let since = (self.clock_count as u32).wrapping_sub(start_clock) as u64;
+                let pos = start as u64 + (since * rate + sub) / per;
+
+                if params.sync == TrackSync::Sync {
+                    // Wrap with the pattern, like the track does at the normal rate.
+                    pos % (self.params.length as u64 * steps / per).max(1)
+                } else {
+                    pos
+                }
+            } else {
+                self.fill_start[i] = None;
+                (clocks * steps + sub) / per
+            };
+
+            let moved = pos != self.track_pos[i];
+            self.track_pos[i] = pos;
+
+            let len = len as u64;
+            if moved {
+                self.trig_due[i] = true;
+
+                // The loop wrapped when the position in it doesn't move forward. This
+                // also catches Sync tracks cut short by the pattern length.
+                let loop_pos = (pos % len) as u8;
+                if self.loop_pos[i].is_some_and(|prev| loop_pos <= prev) {
+                    self.iteration[i] += 1;
+                    let (root, scale) = (self.params.root, self.params.scale);
+                    self.tracks[i].mutate(&mut self.mutate_rng, root, scale);
+                }
+                self.loop_pos[i] = Some(loop_pos);
+            }
+
+            let direction = params.direction.resolve(self.params.direction);
+
+            // Step repeat loops the played position. The track position keeps
+            // going, so releasing lands where the track would have been.
+            let play_pos = match &mut self.repeat {
+                Some(r) if self.selected_tracks.is_selected(i as u8) => {
+                    if moved {
+                        r.moved[i] = r.moved[i].wrapping_add(1);
+                    }
+                    // Positions the direction repeats after.
+                    let period = match direction {
+                        TrackDirection::Pendulum if len > 1 => 2 * (len - 1),
+                        _ => len,
+                    };
+                    r.play_pos(i, period)
+                }
+                _ => pos,
+            };
+
+            let offset = match direction {
+                TrackDirection::Forward | TrackDirection::Inherit => play_pos % len,
+                TrackDirection::Backward => len - 1 - play_pos % len,
+                TrackDirection::Pendulum => {
+                    if len == 1 {
+                        0
+                    } else {
+                        let p = play_pos % (2 * (len - 1));
+                        if p < len {
+                            p
+                        } else {
+                            2 * (len - 1) - p
+                        }
+                    }
+                }
+                TrackDirection::Random => {
+                    if moved {
+                        self.random_offset[i] = self.rng.below(len as u32) as usize;
+                    }
+                    self.random_offset[i] as u64 % len
+                }
+                TrackDirection::Drunk => {
+                    if moved {
+                        let prev = self.random_offset[i] as u64 % len;
+                        let next = (prev + len + self.rng.below(3) as u64 - 1) % len;
+                        self.random_offset[i] = next as usize;
+                    }
+                    self.random_offset[i] as u64 % len
+                }
+            };
+
+            self.track_playhead[i] = first + offset as usize;
+        }
     }
-}
 
-#[derive(Default, Clone, Copy, PartialEq, Eq)]
-enum PlayDirection {
-    #[default]
-    Forward,
-    Backward,
-    Random,
-}
+    /// Playhead back to 0, where a reset takes effect.
+    fn rewind(&mut self, now: Time) {
+        self.playhead = 0;
 
-impl Default for PatternParams {
-    fn default() -> Self {
+        // Step 0 is on the beat, and the next clock is the second pulse of it.
+        self.pulse_count = 1;
+        self.last_beat = now;
+
+        // Conditional trigs count iterations from here.
+        self.iteration = [0; TRACK_COUNT];
+        self.loop_pos = [None; TRACK_COUNT];
+
+        // Tracks restarted go on doubling from their first step.
+        for (track, start) in self.tracks.iter().zip(self.fill_start.iter_mut()) {
+            if track.params.sync != TrackSync::Free {
+                *start = None;
+            }
+        }
+    }
+
+    /// Move cartesian tracks along the row (clock) or between the rows (reset input
+    /// in ResetMode::YClock).
+    fn advance_cartesian(&mut self, along_row: bool) {
+        for i in 0..TRACK_COUNT {
+            if self.tracks[i].params.sync != TrackSync::Cartesian {
+                continue;
+            }
+
+            self.trig_due[i] = true;
+
+            let (x, y) = &mut self.cartesian[i];
+            if along_row {
+                *x = (*x + 1) % 8;
+            } else {
+                *y = (*y + 1) % 2;
+            }
+        }
+    }
+
+    fn handle_button(&mut self, now: Time, row: Row, col: Col, on: bool) {
+        if *row == 4 && *col == 5 && on {
+            // Copy button going down starts a new copy gesture.
+            self.clipboard.begin();
+        }
+
+        if !on && matches!(&self.repeat, Some(r) if r.key == (*row as u8, *col as u8)) {
+            // The step repeat ends when its step is released, whatever the state.
+            self.repeat = None;
+        }
+
+        match self.mstate {
+            MachineState::Normal => self.handle_button_normal(now, row, col, on),
+            MachineState::Shift => self.handle_button_shift(now, row, col, on),
+            MachineState::Velocity => self.handle_button_vel(row, col, on),
+            MachineState::Copy => self.handle_button_copy(now, row, col, on),
+            MachineState::Clear => self.handle_button_clear(row, col, on),
+            MachineState::Fill => self.handle_button_fill(now, row, col, on),
+            _ => {}
+        }
+    }
+
+    fn handle_button_normal(&mut self, now: Time, row: Row, col: Col, on: bool) {
+        match *row {
+            0 | 1 => self.handle_loop_window(row, col, on),
+
+            // Part select. Short push 1-4, long push 5-8.
+            2 => {
+                if on {
+                    self.part_press = Some((col, now));
+                } else if let Some((c, start)) = self.part_press.take() {
+                    if c == col {
+                        let long = now - start >= Time::from_millis(PART_LONG_PRESS_MILLIS);
+                        let half = if long { 4 } else { 0 };
+                        self.selected_part = half + *col - 4;
+                    }
+                }
+            }
+
+            // Track select toggles.
+            3 if on => {
+                let track = (*col - 4) as u8;
+                let selected = self.selected_tracks.is_selected(track);
+                self.selected_tracks.set(track, !selected);
+            }
+
+            _ => {}
+        }
+    }
+
+    /// Hold one step button and press another to set the loop window of the
+    /// selected tracks. The part can be switched in between to make a window
+    /// across parts. Doing it again for the same window clears it.
+    fn handle_loop_window(&mut self, row: Row, col: Col, on: bool) {
+        let step = self.selected_part * 16 + *row * 8 + *col;
+
+        if !on {
+            if matches!(self.window_first, Some((r, c, _)) if r == row && c == col) {
+                self.window_first = None;
+            }
+            return;
+        }
+
+        let Some((_, _, first)) = self.window_first else {
+            self.window_first = Some((row, col, step));
+            return;
+        };
+
+        let window = (first.min(step), first.max(step));
+
+        for i in self.selected_tracks.selected() {
+            let params = &mut self.tracks[i].params;
+            params.loop_window = if params.loop_window == Some(window) {
+                None
+            } else {
+                Some(window)
+            };
+        }
+
+        info!("Loop window: {} - {}", window.0, window.1);
+    }
+
+    /// Hold copy, press the source and then press one or more targets.
+    ///
+    /// * step -> step in the selected part of the selected tracks.
+    /// * part -> part of the selected tracks.
+    /// * track -> track.
+    fn handle_button_copy(&mut self, now: Time, row: Row, col: Col, on: bool) {
+        if !on {
+            return;
+        }
+
+        let Some(place) = self.place_for(row, col) else {
+            return;
+        };
+
+        if self.clipboard.is_copy_next() {
+            if let Some(clip) = self.clip_at(place) {
+                self.clipboard.copy(clip);
+            }
+        } else if !self.paste(place) {
+            self.flash_error(now);
+        }
+    }
+
+    /// Hold velocity and hold one of the first four steps to repeat the last 1, 2, 4
+    /// or 8 steps of the selected tracks.
+    fn handle_button_vel(&mut self, row: Row, col: Col, on: bool) {
+        if !on || *row != 0 || *col >= 4 {
+            return;
+        }
+
+        self.repeat = Some(Repeat {
+            key: (*row as u8, *col as u8),
+            len: 1 << *col,
+            start: self.track_pos,
+            moved: [0; TRACK_COUNT],
+        });
+    }
+
+    /// Hold copy and clear for fill.
+    ///
+    /// * step -> latch fill until the end of the pattern.
+    /// * track -> toggle whether fill doubles the rate of the selected tracks.
+    fn handle_button_fill(&mut self, now: Time, row: Row, col: Col, on: bool) {
+        if !on {
+            return;
+        }
+
+        match *row {
+            0 | 1 => self.fill_latch = true,
+            3 if *col >= 4 => {
+                self.params.fill_double = !self.params.fill_double;
+                self.show_value(now, self.params.fill_double.as_view());
+            }
+            _ => {}
+        }
+    }
+
+    fn is_fill(&self) -> bool {
+        self.mstate == MachineState::Fill || self.fill_latch
+    }
+
+    fn handle_button_shift(&mut self, now: Time, row: Row, col: Col, on: bool) {
+        if !on {
+            return;
+        }
+
+        match (*row, *col) {
+            // Last step toggles play/pause. Resuming continues where we paused.
+            (1, 7) => self.toggle_play(),
+
+            // Second to last step rewinds, the next clock plays from step 0. Use this
+            // while paused to resume from the start.
+            (1, 6) => self.next_is_reset = true,
+
+            // First row transforms the steps of the selected tracks.
+            (0, 0) => self.transform(Transform::ShiftLeft),
+            (0, 1) => self.transform(Transform::ShiftRight),
+            (0, 2) => self.transform(Transform::Reverse),
+            (0, 3) => self.transform(Transform::Invert),
+            (0, 4) => self.transform(Transform::TransposeDown),
+            (0, 5) => self.transform(Transform::TransposeUp),
+            (0, 7) => self.undo_transform(now),
+
+            // Copy is tap tempo.
+            (4, 5) => self.tap(now),
+
+            _ => {}
+        }
+    }
+
+    fn transform(&mut self, transform: Transform) {
+        // Filled in place, it's too big to build on the stack.
+        let undo = &mut self.undo;
+        undo.transform = Some(transform);
+        undo.tracks = [false; TRACK_COUNT];
+
+        for i in self.selected_tracks.selected() {
+            let track = &mut self.tracks[i];
+            let range = transform.range(track, self.selected_part);
+            undo.tracks[i] = true;
+            undo.ranges[i] = (range.start as u8, range.end as u8);
+
+            let steps = &mut track.steps[range];
+            if transform.inverse().is_some() {
+                transform.apply(steps, self.params.scale);
+                continue;
+            }
+
+            // Keep how far each tone moved, to move it back.
+            for (n, step) in steps.iter_mut().enumerate() {
+                let before = *step.tone;
+                transform.apply(core::slice::from_mut(step), self.params.scale);
+                undo.set_moved(i, n, before.abs_diff(*step.tone));
+            }
+        }
+    }
+
+    /// The steps were replaced, so the last transform can't be undone on them.
+    fn drop_undo(&mut self) {
+        self.undo.transform = None;
+    }
+
+    /// Undo the last transform. Only the last one can be undone.
+    fn undo_transform(&mut self, now: Time) {
+        let Some(transform) = self.undo.transform.take() else {
+            self.flash_error(now);
+            return;
+        };
+
+        for i in (0..TRACK_COUNT).filter(|i| self.undo.tracks[*i]) {
+            let (start, end) = self.undo.ranges[i];
+            let steps = &mut self.tracks[i].steps[start as usize..end as usize];
+            match transform.inverse() {
+                Some(inverse) => inverse.apply(steps, self.params.scale),
+                None => {
+                    let up = transform == Transform::TransposeUp;
+                    for (n, step) in steps.iter_mut().enumerate() {
+                        let moved = self.undo.moved(i, n) as i8;
+                        let moved = if up { -moved } else { moved };
+                        step.tone = Val(*step.tone + moved);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tap tempo for the internal clock. Each tap is a beat.
+    fn tap(&mut self, now: Time) {
+        let Some(last) = self.last_tap.replace(now) else {
+            self.taps = 1;
+            return;
+        };
+
+        let interval = now - last;
+
+        if interval > Time::from_millis(TAP_TIMEOUT_MILLIS) {
+            // Start over.
+            self.tap_sum = Time::default();
+            self.taps = 1;
+            return;
+        }
+
+        // Once we have a tempo, reject taps that are too far off it.
+        let bpm = bpm_for(interval);
+        let current = self.settings.bpm;
+        if self.taps >= 3 && (bpm * 3 < current * 2 || bpm * 2 > current * 3) {
+            debug!("Tap outlier: {} vs {}", bpm, current);
+            return;
+        }
+
+        if self.taps < u8::MAX {
+            self.tap_sum = self.tap_sum + interval;
+            self.taps += 1;
+        }
+
+        // Need at least two intervals.
+        if self.taps >= 3 {
+            let average = time_div(self.tap_sum, self.taps as u64 - 1);
+            self.settings.bpm = bpm_for(average);
+            info!("Tap tempo: {}", self.settings.bpm);
+        }
+    }
+
+    fn toggle_play(&mut self) {
+        self.set_play(!self.params.play);
+    }
+
+    fn set_play(&mut self, play: bool) {
+        self.params.play = play;
+
+        if play {
+            // Trigger the step the tracks are on.
+            self.trig_due = [true; TRACK_COUNT];
+        } else {
+            self.gates = [false; TRACK_COUNT];
+        }
+
+        info!("Play: {}", play);
+    }
+
+    /// Hold clear and press what to clear.
+    ///
+    /// * step -> step in the selected part of the selected tracks.
+    /// * part -> part of the selected tracks.
+    /// * track -> track, including params.
+    fn handle_button_clear(&mut self, row: Row, col: Col, on: bool) {
+        if !on {
+            return;
+        }
+
+        let Some(place) = self.place_for(row, col) else {
+            return;
+        };
+
+        match place {
+            Place::Step(row, col) => {
+                for i in self.selected_tracks.selected() {
+                    *step_for(self.selected_part, &mut self.tracks[i], row, col) =
+                        TrackStep::default();
+                }
+            }
+
+            Place::Part(part) => {
+                for i in self.selected_tracks.selected() {
+                    self.tracks[i].steps[part_range(part)].fill(TrackStep::default());
+                }
+            }
+
+            Place::Track(track) => {
+                self.tracks[track].clear();
+            }
+        }
+        self.drop_undo();
+
+        info!("Clear");
+    }
+
+    fn place_for(&self, row: Row, col: Col) -> Option<Place> {
+        match *row {
+            0 | 1 => Some(Place::Step(row, col)),
+            2 => Some(Place::Part(self.part_for(col))),
+            3 => Some(Place::Track(*col - 4)),
+            _ => None,
+        }
+    }
+
+    /// The part buttons only go 1-4, parts 5-8 are reached with a long push. The buttons
+    /// map to the same half as the currently selected part.
+    fn part_for(&self, col: Col) -> usize {
+        let half = if self.selected_part >= 4 { 4 } else { 0 };
+        half + *col - 4
+    }
+
+    fn clip_at(&mut self, place: Place) -> Option<Clip> {
+        let clip = match place {
+            Place::Step(row, col) => {
+                let i = self.selected_tracks.selected().next()?;
+                Clip::Step(*step_for(self.selected_part, &mut self.tracks[i], row, col))
+            }
+            Place::Part(part) => Clip::Part(self.selected_tracks.selected().next()?, part),
+            Place::Track(track) => Clip::Track(track),
+        };
+        Some(clip)
+    }
+
+    /// Paste the clipboard to the place. Returns false if the clipboard content
+    /// doesn't fit the place.
+    fn paste(&mut self, place: Place) -> bool {
+        match (self.clipboard.clip(), place) {
+            (Clip::Step(step), Place::Step(row, col)) => {
+                for i in self.selected_tracks.selected() {
+                    *step_for(self.selected_part, &mut self.tracks[i], row, col) = step;
+                }
+            }
+
+            (Clip::Part(from_track, from_part), Place::Part(to_part)) => {
+                let mut steps = [TrackStep::default(); 16];
+                steps.copy_from_slice(&self.tracks[from_track].steps[part_range(from_part)]);
+
+                for i in self.selected_tracks.selected() {
+                    self.tracks[i].steps[part_range(to_part)].copy_from_slice(&steps);
+                }
+            }
+
+            (Clip::Track(from), Place::Track(to)) => {
+                if from != to {
+                    // Copy in place, a whole track is too big for the stack.
+                    let (low, high) = self.tracks.split_at_mut(from.max(to));
+                    let (src, dst) = if from < to {
+                        (&low[from], &mut high[0])
+                    } else {
+                        (&high[0], &mut low[to])
+                    };
+                    dst.params = src.params;
+                    dst.steps.copy_from_slice(&src.steps);
+                }
+            }
+
+            _ => return false,
+        }
+        self.drop_undo();
+
+        true
+    }
+
+    /// Open the gates of tracks on a new step that triggers, and close them
+    /// after the step length.
+    fn update_gates(&mut self, now: Time) {
+        for i in 0..TRACK_COUNT {
+            if !self.trig_due[i] {
+                if self.gates[i] && self.gate_ended(i, now) {
+                    self.gates[i] = false;
+                }
+                continue;
+            }
+            self.trig_due[i] = false;
+
+            let track = &self.tracks[i];
+            let step = &track.steps[self.track_playhead[i]];
+            let on = track.is_on(self.track_playhead[i]);
+
+            let condition = step.condition();
+            let cond = condition.is_met(self.iteration[i], self.is_fill(), self.pre[i]);
+            let prob = *(track.params.base_probability + step.probability) as u32;
+            let trig = cond && self.rng.below(100) < prob;
+
+            if on && condition.sets_pre() {
+                self.pre[i] = trig;
+            }
+
+            self.gates[i] = on && trig;
+            self.gate_start[i] = now;
+        }
+    }
+
+    /// Whether the open gate of a track has lasted the length of its step. The
+    /// length is a percentage of the step, which is the predicted clock interval
+    /// scaled by the track clock ratio. Legato steps stay open into the next.
+    fn gate_ended(&self, i: usize, now: Time) -> bool {
+        if self.predicted == Time::default() {
+            // No tempo yet, hold until the next step.
+            return false;
+        }
+
+        let track = &self.tracks[i];
+        let step = &track.steps[self.track_playhead[i]];
+        if step.legato {
+            return false;
+        }
+
+        let length = *(track.params.base_step_length + step.length) as u64;
+        let (steps, per) = self.step_ratio[i];
+
+        time_mul(now - self.gate_start[i], 100 * steps as u64)
+            >= time_mul(self.predicted, length * per as u64)
+    }
+
+    fn handle_rotary(&mut self, now: Time, row: Row, col: Col, v: i8) {
+        match self.mstate {
+            MachineState::Normal => self.handle_rotary_normal(now, row, col, v),
+            MachineState::Shift => self.handle_rotary_shift(now, row, col, v),
+            MachineState::Velocity => self.handle_rotary_vel(now, row, col, v),
+            MachineState::HoldUpper(_) if *row == 1 => self.handle_rotary_hold(now, true, col, v),
+            MachineState::HoldLower(_) if *row == 0 => self.handle_rotary_hold(now, false, col, v),
+            MachineState::Reset => self.handle_rotary_global(now, row, col, v),
+            MachineState::Euclid if *row == 0 => self.handle_rotary_euclid(now, col, v),
+            _ => {}
+        }
+    }
+
+    fn handle_rotary_normal(&mut self, now: Time, row: Row, col: Col, v: i8) {
+        for i in self.selected_tracks.selected() {
+            let step = step_for(self.selected_part, &mut self.tracks[i], row, col);
+            step.tone.add(v);
+        }
+
+        self.show_step_value(now, row, col, |s| Some(s.tone.as_view()));
+    }
+
+    fn handle_rotary_shift(&mut self, now: Time, row: Row, col: Col, v: i8) {
+        if *row == 0 {
+            // Track functions.
+            let part = self.selected_part;
+            for i in self.selected_tracks.selected() {
+                let track = &mut self.tracks[i];
+                match *col {
+                    0 => track.params.add_length(v),
+                    1 => track.params.base_velocity.add(v),
+                    2 => track.params.base_probability.add(v),
+                    3 => track.params.base_slew.add(v),
+                    4 => {
+                        track.params.sync.add(v);
+                        if track.params.sync == TrackSync::Cartesian {
+                            // The grid is the part being viewed when cartesian is picked.
+                            track.params.cartesian_part = part;
+                        }
+                    }
+                    5 => track.params.lfo_mode = v > 0,
+                    6 => track.params.base_step_length.add(v),
+                    7 => track.params.clock_ratio.add(v),
+                    _ => {}
+                }
+            }
+
+            // Show the value of the first selected track.
+            let Some(i) = self.selected_tracks.selected().next() else {
+                return;
+            };
+            let params = &self.tracks[i].params;
+            let view = match *col {
+                0 => ValueView::Bar(params.track_length as i32, 128),
+                1 => params.base_velocity.as_view(),
+                2 => params.base_probability.as_view(),
+                3 => params.base_slew.as_view(),
+                4 => params.sync.as_view(),
+                5 => params.lfo_mode.as_view(),
+                6 => params.base_step_length.as_view(),
+                7 => params.clock_ratio.as_view(),
+                _ => return,
+            };
+            self.show_value(now, view);
+        } else if *row == 1 {
+            // Global/pattern functions
+            match *col {
+                0 => self.params.add_length(v),
+                1 => self.params.swing.add(v),
+                2 => self.params.direction.add(v),
+                3 => {
+                    if self.params.play != (v > 0) {
+                        self.toggle_play();
+                    }
+                }
+                4 => self.settings.add_bpm(v),
+                5 => self.settings.brightness.add(v),
+                6 => self.params.scale.add(v),
+                7 => self.params.root.add(v),
+                _ => {}
+            }
+
+            let view = match *col {
+                0 => ValueView::Bar(self.params.length as i32, 128),
+                1 => self.params.swing.as_view(),
+                2 => self.params.direction.as_view(),
+                3 => self.params.play.as_view(),
+                4 => ValueView::Bar(self.settings.bpm as i32 - 20, 280),
+                5 => self.settings.brightness.as_view(),
+                6 => self.params.scale.as_view(),
+                7 => self.params.root.as_view(),
+                _ => return,
+            };
+            self.show_value(now, view);
+        }
+    }
+
+    /// Global settings in reset mode (shift + velocity).
+    fn handle_rotary_global(&mut self, now: Time, row: Row, col: Col, v: i8) {
+        if *row == 0 && *col == 4 {
+            self.handle_rotary_mutate(now, v);
+            return;
+        }
+
+        let settings = &mut self.settings;
+        let view = match (*row, *col) {
+            (1, 0) => {
+                settings.ppqn.add(v);
+                settings.ppqn.as_view()
+            }
+            (1, 1) => {
+                settings.steps_per_beat.add(v);
+                settings.steps_per_beat.as_view()
+            }
+            (1, 2) => {
+                settings.reset_mode.add(v);
+                settings.reset_mode.as_view()
+            }
+            _ => return,
+        };
+        self.show_value(now, view);
+    }
+
+    /// In euclid mode (shift + clear), encoder 1-3 edit hits, steps and rotation.
+    /// The generator plays instead of the steps until committed, so the steps are
+    /// left as they are.
+    fn handle_rotary_euclid(&mut self, now: Time, col: Col, v: i8) {
+        for i in self.selected_tracks.selected() {
+            let params = &mut self.tracks[i].params;
+            let len = params.track_length;
+            let e = params.euclid.get_or_insert_with(|| Euclid::new(len));
+            match *col {
+                0 => e.add_hits(v),
+                1 => e.add_steps(v),
+                2 => e.add_rotation(v),
+                _ => {}
+            }
+        }
+
+        let Some(i) = self.selected_tracks.selected().next() else {
+            return;
+        };
+        let Some(e) = &self.tracks[i].params.euclid else {
+            return;
+        };
+        let view = match *col {
+            0 => ValueView::Bar(e.hits as i32, e.steps as i32),
+            1 => ValueView::Bar(e.steps as i32, 128),
+            2 => ValueView::Bar(e.rotation as i32, e.steps as i32),
+            _ => return,
+        };
+        self.show_value(now, view);
+    }
+
+    /// Mutate amount of the selected tracks on encoder 5.
+    fn handle_rotary_mutate(&mut self, now: Time, v: i8) {
+        for i in self.selected_tracks.selected() {
+            self.tracks[i].params.mutate.add(v);
+        }
+
+        if let Some(i) = self.selected_tracks.selected().next() {
+            self.show_value(now, self.tracks[i].params.mutate.as_view());
+        }
+    }
+
+    /// In reset mode, push encoder 5 to toggle the mutate lock of the selected tracks.
+    fn handle_rotary_button_global(&mut self, now: Time, row: Row, col: Col) {
+        if *row != 0 || *col != 4 {
+            return;
+        }
+
+        for i in self.selected_tracks.selected() {
+            let params = &mut self.tracks[i].params;
+            params.mutate_lock = !params.mutate_lock;
+        }
+
+        if let Some(i) = self.selected_tracks.selected().next() {
+            self.show_value(now, self.tracks[i].params.mutate_lock.as_view());
+        }
+    }
+
+    /// In euclid mode, push encoder 1-3 to commit the euclidean generator of the
+    /// selected tracks, encoder 4 to discard it.
+    fn handle_rotary_button_euclid(&mut self, row: Row, col: Col) {
+        if *row != 0 {
+            return;
+        }
+
+        for i in self.selected_tracks.selected() {
+            let track = &mut self.tracks[i];
+            match *col {
+                0..=2 => track.commit_euclid(),
+                3 => track.params.euclid = None,
+                _ => {}
+            }
+        }
+
+        if *col <= 2 {
+            self.drop_undo();
+        }
+    }
+
+    fn handle_rotary_button_shift(&mut self, now: Time, row: Row, col: Col) {
+        // Push the track sync encoder to cycle the track direction.
+        if *row == 0 && *col == 4 {
+            for i in self.selected_tracks.selected() {
+                self.tracks[i].params.direction.add(1);
+            }
+            if let Some(i) = self.selected_tracks.selected().next() {
+                self.show_value(now, self.tracks[i].params.direction.as_view());
+            }
+        }
+
+        // Push the BPM encoder to cycle the clock source.
+        if *row == 1 && *col == 4 {
+            self.settings.clock_source.add(1);
+            self.show_value(now, self.settings.clock_source.as_view());
+        }
+    }
+
+    fn handle_rotary_vel(&mut self, now: Time, row: Row, col: Col, v: i8) {
+        for i in self.selected_tracks.selected() {
+            let step = step_for(self.selected_part, &mut self.tracks[i], row, col);
+            step.velocity.add(v);
+        }
+
+        self.show_step_value(now, row, col, |s| Some(s.velocity.as_view()));
+    }
+
+    fn handle_rotary_hold(&mut self, now: Time, upper: bool, col: Col, v: i8) {
+        // NB we won't get here if it's a rotary on the same road as the
+        // pushed down one.
+        let row = if upper { Row(1) } else { Row(0) };
+        let Some(s) = self.buttons.rotary_to_step() else {
+            return;
+        };
+
+        for i in self.selected_tracks.selected() {
+            let step = step_for(self.selected_part, &mut self.tracks[i], row, col);
+            match s {
+                0 => step.length.add(v),
+                1 => step.velocity.add(v),
+                2 => step.probability.add(v),
+                3 => step.slew.add(v),
+                4 => step.add_condition(v),
+                // 5 => spread cannot be added on individual track level.
+                6 => step.scale.add(v),
+                7 => step.tone.add(v),
+                _ => {}
+            }
+        }
+
+        self.show_step_value(now, row, col, |step| match s {
+            0 => Some(step.length.as_view()),
+            1 => Some(step.velocity.as_view()),
+            2 => Some(step.probability.as_view()),
+            3 => Some(step.slew.as_view()),
+            4 => Some(step.condition().as_view()),
+            6 => Some(step.scale.as_view()),
+            7 => Some(step.tone.as_view()),
+            _ => None,
+        });
+    }
+
+    /// Show a value of the step in the first selected track.
+    fn show_step_value(
+        &mut self,
+        now: Time,
+        row: Row,
+        col: Col,
+        f: impl Fn(&TrackStep) -> Option<ValueView>,
+    ) {
+        let Some(i) = self.selected_tracks.selected().next() else {
+            return;
+        };
+        let step = step_for(self.selected_part, &mut self.tracks[i], row, col);
+        if let Some(view) = f(step) {
+            self.show_value(now, view);
+        }
+    }
+}
+
+fn step_for(selected_part: usize, track: &mut Track, row: Row, col: Col) -> &mut TrackStep {
+    // 16 steps per part, 8 per row.
+    let offset_part = selected_part * 16;
+    &mut track.steps[offset_part + *row * 8 + *col]
+}
+
+/// Time multiplied by n.
+pub(crate) fn time_mul(t: Time, n: u64) -> Time {
+    // Double and add, since this runs for the gates every loop.
+    let (mut t, mut n) = (t, n);
+    let mut acc = Time::default();
+    while n > 0 {
+        if n & 1 == 1 {
+            acc = acc + t;
+        }
+        t = t + t;
+        n >>= 1;
+    }
+    acc
+}
+
+/// Divide a time, rounded down. Time has no division, so this searches for the
+/// largest micros that multiplied stays within `t`.
+pub(crate) fn time_div(t: Time, n: u64) -> Time {
+    let (mut lo, mut hi) = (0_i64, 1_i64 << 32);
+    while lo < hi {
+        let mid = (lo + hi + 1) / 2;
+        if time_mul(Time::from_micros(mid), n) <= t {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    Time::from_micros(lo)
+}
+
+/// BPM for a beat interval, rounded up.
+fn bpm_for(interval: Time) -> u16 {
+    (20..=300)
+        .find(|bpm| Time::from_micros(60_000_000 / *bpm as i64) <= interval)
+        .unwrap_or(300)
+}
+
+fn part_range(part: usize) -> core::ops::Range<usize> {
+    let start = part * 16;
+    start..(start + 16)
+}
+
+#[derive(Clone, Copy)]
+struct ResetHold {
+    kind: ResetKind,
+    start: Time,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ResetKind {
+    Bank,
+    Factory,
+}
+
+impl ResetHold {
+    /// Progress of the hold, 0-16.
+    fn progress(&self, now: Time) -> usize {
+        let millis = match self.kind {
+            ResetKind::Bank => BANK_RESET_MILLIS,
+            ResetKind::Factory => FACTORY_RESET_MILLIS,
+        };
+        let lapsed = now - self.start;
+        (1..=16)
+            .take_while(|i| lapsed >= Time::from_millis(millis * i / 16))
+            .count()
+    }
+}
+
+/// What it takes to undo a transform.
+struct Undo {
+    /// The transform to undo, None when there is nothing to undo.
+    transform: Option<Transform>,
+
+    /// The tracks that were transformed.
+    tracks: [bool; TRACK_COUNT],
+
+    /// Steps transformed in each track, start and end. Kept since the play
+    /// range can change before the undo.
+    ranges: [(u8, u8); TRACK_COUNT],
+
+    /// Semitones each step moved, for transposes which have no inverse. A
+    /// degree is at most 12 semitones, so two steps share a byte.
+    moved: [[u8; 64]; TRACK_COUNT],
+}
+
+impl Default for Undo {
+    fn default() -> Self {
+        Undo {
+            transform: None,
+            tracks: [false; TRACK_COUNT],
+            ranges: [(0, 0); TRACK_COUNT],
+            moved: [[0; 64]; TRACK_COUNT],
+        }
+    }
+}
+
+impl Undo {
+    fn set_moved(&mut self, track: usize, n: usize, semis: u8) {
+        let byte = &mut self.moved[track][n / 2];
+        let shift = (n % 2) * 4;
+        *byte = (*byte & !(0xf << shift)) | (semis << shift);
+    }
+
+    fn moved(&self, track: usize, n: usize) -> u8 {
+        (self.moved[track][n / 2] >> ((n % 2) * 4)) & 0xf
+    }
+}
+
+/// Step repeat of the last steps before the press.
+struct Repeat {
+    /// The step button (row, col) held down.
+    key: (u8, u8),
+
+    /// Number of steps to repeat. 1, 2, 4 or 8.
+    len: u8,
+
+    /// Position of each track at the press.
+    start: [u64; TRACK_COUNT],
+
+    /// Steps each track has moved since the press. Counted rather than taken
+    /// from the position, which wraps with the pattern. Wraps, which is fine
+    /// since len divides 256.
+    moved: [u8; TRACK_COUNT],
+}
+
+impl Repeat {
+    /// Position to play for a track. Going back before the start wraps into the
+    /// previous pass of the loop, which repeats every `period` positions.
+    fn play_pos(&self, track: usize, period: u64) -> u64 {
+        // Steps back from the start, the start itself is played on the press.
+        let len = self.len as u64;
+        let back = len - 1 - (self.moved[track] as u64 + len - 1) % len;
+        self.start[track] + period * back.div_ceil(period) - back
+    }
+}
+
+/// A place on the panel that can be copied from or pasted to.
+#[derive(Clone, Copy)]
+enum Place {
+    /// Step in the selected part.
+    Step(Row, Col),
+    /// Part index 0-7.
+    Part(usize),
+    /// Track index 0-3.
+    Track(usize),
+}
+
+struct PatternParams {
+    /// Length of entire pattern. 1-128
+    pub length: usize,
+
+    /// Percentage swing. Default 50.
+    ///
+    /// 50% is perfectly straight < 50 note is early > 50 note is late.
+    pub swing: Val<1, 99>,
+
+    /// How the pattern is played. Default is forward.
+    pub direction: PlayDirection,
+
+    /// Whether we are playing or paused right now. Defaults to true.
+    pub play: bool,
+
+    /// Whether fill doubles the clock rate of the selected tracks.
+    pub fill_double: bool,
+
+    /// The root key.
+    pub root: Tone,
+
+    /// The default scale for the pattern. Can be overridden by step.
+    pub scale: Scale,
+}
+
+impl PatternParams {
+    pub fn add_length(&mut self, v: i8) {
+        self.length = (self.length as isize)
+            .saturating_add(v as isize)
+            .clamp(1, 128) as usize;
+    }
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum PlayDirection {
+    #[default]
+    Forward,
+    Backward,
+    Random,
+}
+
+impl TrackDirection {
+    /// The direction to play, with inherit resolved from the pattern direction.
+    fn resolve(self, pattern: PlayDirection) -> TrackDirection {
+        match (self, pattern) {
+            (TrackDirection::Inherit, PlayDirection::Forward) => TrackDirection::Forward,
+            (TrackDirection::Inherit, PlayDirection::Backward) => TrackDirection::Backward,
+            (TrackDirection::Inherit, PlayDirection::Random) => TrackDirection::Random,
+            (d, _) => d,
+        }
+    }
+}
+
+impl AsView for PlayDirection {
+    fn as_view(&self) -> ValueView {
+        ValueView::OneHot(*self as usize, 3)
+    }
+}
+
+impl PlayDirection {
+    /// Cycle through the directions.
+    pub fn add(&mut self, v: i8) {
+        use PlayDirection::*;
+        *self = match (*self as i16 + v as i16).rem_euclid(3) {
+            0 => Forward,
+            1 => Backward,
+            _ => Random,
+        };
+    }
+}
+
+impl Default for PatternParams {
+    fn default() -> Self {
         Self {
             length: 16,
             swing: Val(50),
             direction: PlayDirection::default(),
             play: true,
+            fill_double: false,
             root: Tone(0), // C2
             scale: Scale::Major,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const INTERVAL: i64 = 125;
+
+    /// State with the given reset mode, after `clocks` clocks.
+    fn state(mode: ResetMode, clocks: i64) -> (AppState, Time) {
+        let mut state = AppState::new(1);
+        state.settings.reset_mode = mode;
+        let mut now = Time::default();
+        for _ in 0..clocks {
+            now = clock(&mut state, now);
+        }
+        (state, now)
+    }
+
+    fn clock(state: &mut AppState, now: Time) -> Time {
+        let now = now + Time::from_millis(INTERVAL);
+        state.apply_oper(now, Oper::Clock(Time::from_millis(INTERVAL)));
+        now
+    }
+
+    #[test]
+    fn repeat_wraps_into_previous_loop() {
+        let repeat = Repeat {
+            key: (0, 3),
+            len: 8,
+            start: [2; TRACK_COUNT],
+            moved: [0; TRACK_COUNT],
+        };
+
+        // The press plays the start, then the 8 steps up to it, from the end of
+        // the previous pass of a 16 step loop.
+        assert_eq!(repeat.play_pos(0, 16) % 16, 2);
+
+        let mut played = [0; 8];
+        for (n, p) in played.iter_mut().enumerate() {
+            let r = Repeat {
+                moved: [n as u8 + 1; TRACK_COUNT],
+                ..repeat
+            };
+            *p = r.play_pos(0, 16) % 16;
+        }
+        assert_eq!(played, [11, 12, 13, 14, 15, 0, 1, 2]);
+    }
+
+    #[test]
+    fn reset_next_clock() {
+        let (mut state, now) = state(ResetMode::NextClock, 3);
+        let before = state.playhead();
+
+        state.apply_oper(now, Oper::Reset);
+        assert_eq!(state.playhead(), before);
+
+        let now = clock(&mut state, now);
+        assert_eq!(state.playhead(), 0);
+
+        clock(&mut state, now);
+        assert_eq!(state.playhead(), 1);
+    }
+
+    #[test]
+    fn reset_immediate() {
+        let (mut state, now) = state(ResetMode::Immediate, 3);
+
+        state.apply_oper(now, Oper::Reset);
+        assert_eq!(state.playhead(), 0);
+
+        clock(&mut state, now);
+        assert_eq!(state.playhead(), 1);
+    }
+
+    #[test]
+    fn reset_immediate_retriggers() {
+        let (mut state, now) = state(ResetMode::Immediate, 0);
+        state.tracks[0].steps[0].on = true;
+
+        state.apply_oper(now, Oper::Reset);
+        assert!(state.gates[0]);
+
+        // Already on step 0.
+        state.gates[0] = false;
+        state.apply_oper(now, Oper::Reset);
+        assert!(state.gates[0]);
+    }
+
+    #[test]
+    fn reset_aligns_beat() {
+        // Reset off the beat, the step 0 it goes to is on the beat.
+        let (mut state, now) = state(ResetMode::NextClock, 2);
+        state.apply_oper(now, Oper::Reset);
+        let mut now = clock(&mut state, now);
+        assert!(state.last_beat == now);
+
+        let beat = now;
+        for _ in 0..3 {
+            now = clock(&mut state, now);
+        }
+        assert!(state.last_beat == beat);
+
+        now = clock(&mut state, now);
+        assert!(state.last_beat == now);
+    }
+
+    #[test]
+    fn reset_beat() {
+        // 4 steps per beat, the 5th clock is on the next beat.
+        let (mut state, now) = state(ResetMode::Beat, 2);
+        let before = state.playhead();
+
+        state.apply_oper(now, Oper::Reset);
+        let now = clock(&mut state, now);
+        let now = clock(&mut state, now);
+        assert_eq!(state.playhead(), before + 2);
+
+        let now = clock(&mut state, now);
+        assert_eq!(state.playhead(), 0);
+
+        clock(&mut state, now);
+        assert_eq!(state.playhead(), 1);
+    }
+
+    #[test]
+    fn reset_beat_counts_iterations_from_the_rewind() {
+        let (mut state, now) = state(ResetMode::Beat, 2);
+        state.tracks[0].params.track_length = 4;
+
+        // The reset waits for the beat, the track moves on meanwhile.
+        state.apply_oper(now, Oper::Reset);
+        let now = clock(&mut state, now);
+        let now = clock(&mut state, now);
+        clock(&mut state, now);
+        assert_eq!(state.playhead(), 0);
+        assert_eq!(state.iteration[0], 0);
+    }
+
+    #[test]
+    fn reset_run_stop() {
+        let (mut state, now) = state(ResetMode::RunStop, 3);
+        state.set_play(false);
+
+        state.apply_oper(now, Oper::Reset);
+        assert!(state.params.play);
+
+        let now = clock(&mut state, now);
+        assert_eq!(state.playhead(), 0);
+
+        state.apply_oper(now, Oper::ResetEnd);
+        assert!(!state.params.play);
+
+        clock(&mut state, now);
+        assert_eq!(state.playhead(), 0);
+    }
+
+    #[test]
+    fn reset_y_clock() {
+        let (mut state, now) = state(ResetMode::YClock, 3);
+        state.tracks[1].params.sync = TrackSync::Cartesian;
+        let before = state.playhead();
+
+        state.apply_oper(now, Oper::Reset);
+        assert_eq!(state.cartesian[1], (0, 1));
+
+        // The other tracks don't see the reset.
+        clock(&mut state, now);
+        assert_eq!(state.playhead(), before + 1);
+    }
+
+    #[test]
+    fn reset_next_pattern() {
+        let (mut state, now) = state(ResetMode::NextPattern, 3);
+        let before = state.playhead();
+
+        state.apply_oper(now, Oper::Reset);
+        assert_eq!(state.playhead(), before);
+
+        clock(&mut state, now);
+        assert_eq!(state.playhead(), before + 1);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::music::{Scale, Tone};
+use crate::rng::Rng;
 use crate::val::Val;
 
 pub struct Track {
@@ -8,13 +10,37 @@ pub struct Track {
     pub steps: [TrackStep; 128],
 }
 
+#[derive(Clone, Copy)]
 pub struct TrackParams {
     /// Length of track. In clock-ticks.
     pub track_length: usize,
 
+    /// Loop window as first and last step (inclusive). Overrides the track length.
+    pub loop_window: Option<(usize, usize)>,
+
     /// Track sync parameter.
     pub sync: TrackSync,
 
+    /// Part played as a grid in TrackSync::Cartesian.
+    pub cartesian_part: usize,
+
+    /// Clock ratio of the track against the pattern clock. Defaults to x1.
+    pub clock_ratio: ClockRatio,
+
+    /// Play direction. Defaults to inherit from the pattern.
+    pub direction: TrackDirection,
+
+    /// Probability that a step gets a new random tone each time the track loops.
+    /// Defaults to 0, no mutation.
+    pub mutate: Val<0, 100>,
+
+    /// Freezes the mutated tones.
+    pub mutate_lock: bool,
+
+    /// Euclidean generator being auditioned. Plays instead of the step on/off
+    /// until committed.
+    pub euclid: Option<Euclid>,
+
     /// Base step length. Defaults to 50.
     pub base_step_length: Val<0, 100>,
 
@@ -46,6 +72,76 @@ impl TrackParams {
             .saturating_add(v as isize)
             .clamp(1, 128) as usize;
     }
+
+    /// First step and number of steps played. The steps of an auditioned
+    /// generator, otherwise the loop window if set, otherwise from 0 to the track
+    /// length.
+    pub fn play_range(&self) -> (usize, usize) {
+        if let Some(e) = &self.euclid {
+            // Plays like it will once committed.
+            return (0, e.steps);
+        }
+        match self.loop_window {
+            Some((first, last)) => (first, last - first + 1),
+            None => (0, self.track_length),
+        }
+    }
+}
+
+impl Track {
+    /// Back to default params and steps. Done in place, since a whole track is
+    /// too big to build on the stack.
+    pub fn clear(&mut self) {
+        self.params = TrackParams::default();
+        self.steps.fill(TrackStep::default());
+    }
+
+    /// Whether step i is on, taking an auditioned generator into account.
+    pub fn is_on(&self, i: usize) -> bool {
+        match &self.params.euclid {
+            Some(e) => e.is_hit(i),
+            None => self.steps[i].on,
+        }
+    }
+
+    /// Turing machine style mutation. Each step in the play range gets a random
+    /// tone in the scale, up to two octaves above the root, with the mutate
+    /// amount as probability. The same seed gives the same mutations.
+    pub fn mutate(&mut self, rng: &mut Rng, root: Tone, scale: Scale) {
+        if self.params.mutate_lock || *self.params.mutate == 0 {
+            return;
+        }
+
+        let amount = *self.params.mutate as u32;
+        let tones = scale.as_tones();
+        let (first, len) = self.params.play_range();
+
+        for step in &mut self.steps[first..(first + len)] {
+            if rng.below(100) >= amount {
+                continue;
+            }
+
+            let n = rng.below(tones.len() as u32 * 2) as usize;
+            let octave = (n / tones.len()) as i8 * 12;
+
+            let mut tone = root;
+            tone.add(*tones[n % tones.len()] + octave);
+            step.tone = Val(*tone - *root);
+        }
+    }
+
+    /// Write the generator to the steps and stop auditioning it. The steps become
+    /// the track length, and the loop window is dropped.
+    pub fn commit_euclid(&mut self) {
+        let Some(e) = self.params.euclid.take() else {
+            return;
+        };
+        for i in 0..e.steps {
+            self.steps[i].on = e.is_hit(i);
+        }
+        self.params.track_length = e.steps;
+        self.params.loop_window = None;
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -91,6 +187,10 @@ pub struct TrackStep {
     /// Added to track level slew. The end result i 0 - 100.
     pub slew: Val<-100, 100>,
 
+    /// Condition for the step to trigger, as TrigCondition::index(). Applies on
+    /// top of probability. Kept as an index, since the enum takes 3 bytes.
+    pub condition: u8,
+
     /// Micro offset. Defaults to 0.
     ///
     /// * -128 same time as previous step, i.e. -127 the min reasonable.
@@ -107,6 +207,248 @@ pub enum TrackSync {
     Reset,
     /// Track just keeps looping, ignoring both pattern length and reset.
     Free,
+    /// The 2x8 steps of one part as a grid. Clock moves along the row
+    /// (X), the reset input in the Y clock reset mode moves between the rows (Y). Track length and clock ratio
+    /// don't apply.
+    Cartesian,
+}
+
+/// Euclidean rhythm, K hits spread as evenly as possible over N steps.
+#[derive(Clone, Copy)]
+pub struct Euclid {
+    /// Number of hits, K.
+    pub hits: usize,
+
+    /// Number of steps, N. Becomes the track length when committed.
+    pub steps: usize,
+
+    /// Steps to rotate the hits to the right.
+    pub rotation: usize,
+}
+
+impl Euclid {
+    pub fn new(steps: usize) -> Self {
+        Euclid {
+            hits: 0,
+            steps,
+            rotation: 0,
+        }
+    }
+
+    /// Whether step i is a hit. The pattern repeats every N steps.
+    pub fn is_hit(&self, i: usize) -> bool {
+        let n = self.steps;
+        let i = (i + n - self.rotation % n) % n;
+        (i * self.hits) % n < self.hits
+    }
+
+    pub fn add_hits(&mut self, v: i8) {
+        self.hits = (self.hits as isize + v as isize).clamp(0, self.steps as isize) as usize;
+    }
+
+    pub fn add_steps(&mut self, v: i8) {
+        self.steps = (self.steps as isize + v as isize).clamp(1, 128) as usize;
+        self.hits = self.hits.min(self.steps);
+        self.rotation %= self.steps;
+    }
+
+    pub fn add_rotation(&mut self, v: i8) {
+        self.rotation =
+            (self.rotation as isize + v as isize).rem_euclid(self.steps as isize) as usize;
+    }
+}
+
+impl TrackStep {
+    pub fn condition(&self) -> TrigCondition {
+        TrigCondition::from_index(self.condition as usize)
+    }
+
+    pub fn add_condition(&mut self, v: i8) {
+        self.condition = (self.condition as i16 + v as i16).clamp(0, CONDITION_COUNT - 1) as u8;
+    }
+}
+
+/// Condition for a step to trigger.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum TrigCondition {
+    #[default]
+    Always,
+    /// Play on iteration A of every B (A:B). Both start at 1, and A <= B <= 8.
+    Iteration(u8, u8),
+    /// Only the first iteration after reset.
+    First,
+    /// Every iteration except the first.
+    NotFirst,
+    /// Only when fill is active.
+    Fill,
+    /// Only when fill is not active.
+    NotFill,
+    /// When the previous condition on the track was true.
+    Pre,
+    /// When the previous condition on the track was false.
+    NotPre,
+}
+
+/// The 7 plain conditions, then 1:2, 2:2, 1:3 … 8:8.
+const CONDITION_COUNT: i16 = 7 + 35;
+
+impl TrigCondition {
+    /// Position in the order the encoder goes through the conditions.
+    pub fn index(&self) -> usize {
+        use TrigCondition::*;
+        match *self {
+            Always => 0,
+            First => 1,
+            NotFirst => 2,
+            Fill => 3,
+            NotFill => 4,
+            Pre => 5,
+            NotPre => 6,
+            Iteration(a, b) => 7 + (b as usize * (b as usize - 1) / 2 - 1) + (a as usize - 1),
+        }
+    }
+
+    pub fn from_index(i: usize) -> Self {
+        use TrigCondition::*;
+        match i {
+            0 => Always,
+            1 => First,
+            2 => NotFirst,
+            3 => Fill,
+            4 => NotFill,
+            5 => Pre,
+            6 => NotPre,
+            _ => {
+                let mut a = i - 7;
+                let mut b = 2;
+                while a >= b {
+                    a -= b;
+                    b += 1;
+                }
+                Iteration(a as u8 + 1, b as u8)
+            }
+        }
+    }
+
+    /// Whether the condition holds. Iteration is 0 for the first loop of the track.
+    pub fn is_met(&self, iteration: u32, fill: bool, pre: bool) -> bool {
+        use TrigCondition::*;
+        match *self {
+            Always => true,
+            Iteration(a, b) => iteration % b as u32 == a as u32 - 1,
+            First => iteration == 0,
+            NotFirst => iteration > 0,
+            Fill => fill,
+            NotFill => !fill,
+            Pre => pre,
+            NotPre => !pre,
+        }
+    }
+
+    /// Whether the outcome is what following Pre/NotPre conditions look at.
+    pub fn sets_pre(&self) -> bool {
+        use TrigCondition::*;
+        !matches!(self, Always | Pre | NotPre)
+    }
+}
+
+/// Play direction of a track.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum TrackDirection {
+    /// Same as the pattern.
+    #[default]
+    Inherit,
+    Forward,
+    Backward,
+    /// Forward then backward, without repeating the end steps.
+    Pendulum,
+    /// Any step in the track.
+    Random,
+    /// Random walk one step forward, backward or staying.
+    Drunk,
+}
+
+impl TrackDirection {
+    /// Cycle through the directions.
+    pub fn add(&mut self, v: i8) {
+        use TrackDirection::*;
+        *self = match (*self as i16 + v as i16).rem_euclid(6) {
+            0 => Inherit,
+            1 => Forward,
+            2 => Backward,
+            3 => Pendulum,
+            4 => Random,
+            _ => Drunk,
+        };
+    }
+}
+
+/// Clock divider/multiplier for a track.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum ClockRatio {
+    Div16,
+    Div8,
+    Div4,
+    Div3,
+    Div2,
+    /// Dotted, 2 steps in the time of 3 clocks.
+    Dotted,
+    #[default]
+    X1,
+    /// Triplet, 3 steps in the time of 2 clocks.
+    Triplet,
+    X2,
+    X3,
+    X4,
+}
+
+impl ClockRatio {
+    /// Track steps per clocks, as (steps, clocks).
+    pub fn ratio(&self) -> (u64, u64) {
+        match self {
+            ClockRatio::Div16 => (1, 16),
+            ClockRatio::Div8 => (1, 8),
+            ClockRatio::Div4 => (1, 4),
+            ClockRatio::Div3 => (1, 3),
+            ClockRatio::Div2 => (1, 2),
+            ClockRatio::Dotted => (2, 3),
+            ClockRatio::X1 => (1, 1),
+            ClockRatio::Triplet => (3, 2),
+            ClockRatio::X2 => (2, 1),
+            ClockRatio::X3 => (3, 1),
+            ClockRatio::X4 => (4, 1),
+        }
+    }
+
+    pub fn add(&mut self, v: i8) {
+        use ClockRatio::*;
+        *self = match (*self as i8).saturating_add(v).clamp(0, 10) {
+            0 => Div16,
+            1 => Div8,
+            2 => Div4,
+            3 => Div3,
+            4 => Div2,
+            5 => Dotted,
+            6 => X1,
+            7 => Triplet,
+            8 => X2,
+            9 => X3,
+            _ => X4,
+        };
+    }
+}
+
+impl TrackSync {
+    /// Cycle through the modes.
+    pub fn add(&mut self, v: i8) {
+        use TrackSync::*;
+        *self = match (*self as i16 + v as i16).rem_euclid(4) {
+            0 => Sync,
+            1 => Reset,
+            2 => Free,
+            _ => Cartesian,
+        };
+    }
 }
 
 impl Default for Track {
@@ -122,7 +464,14 @@ impl Default for TrackParams {
     fn default() -> Self {
         Self {
             track_length: 128,
+            loop_window: None,
             sync: TrackSync::default(),
+            cartesian_part: 0,
+            clock_ratio: ClockRatio::default(),
+            direction: TrackDirection::default(),
+            mutate: Val(0),
+            mutate_lock: false,
+            euclid: None,
             base_step_length: Val(50),
             base_velocity: Val(80),
             lfo_mode: false,
@@ -144,7 +493,75 @@ impl Default for TrackStep {
             legato: Default::default(),
             velocity: Val(0),
             slew: Val(0),
+            condition: 0, // Always
             offset: Val(0),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mutated(seed: u32, params: TrackParams) -> Track {
+        let mut track = Track::default();
+        track.params = params;
+        track.mutate(&mut Rng::new(seed), Tone(0), Scale::Major);
+        track
+    }
+
+    fn tones(track: &Track) -> [i8; 128] {
+        let mut tones = [0; 128];
+        for (t, step) in tones.iter_mut().zip(track.steps.iter()) {
+            *t = *step.tone;
+        }
+        tones
+    }
+
+    #[test]
+    fn mutate_fixed_seed() {
+        let params = TrackParams {
+            mutate: Val(100),
+            ..Default::default()
+        };
+
+        let track = mutated(1234, params);
+
+        // The same seed gives the same tones.
+        assert_eq!(tones(&track), tones(&mutated(1234, params)));
+        assert_ne!(tones(&track), tones(&mutated(4321, params)));
+
+        // All in the scale, within two octaves of the root.
+        let scale = Scale::Major.as_tones();
+        for step in &track.steps {
+            let tone = *step.tone;
+            assert!((0..24).contains(&tone));
+            assert!(scale.iter().any(|t| **t == tone % 12));
+        }
+    }
+
+    #[test]
+    fn mutate_play_range_only() {
+        let params = TrackParams {
+            mutate: Val(100),
+            track_length: 16,
+            ..Default::default()
+        };
+
+        let track = mutated(1234, params);
+        assert!(track.steps[16..].iter().all(|s| *s.tone == 0));
+    }
+
+    #[test]
+    fn mutate_off_or_locked() {
+        let off = TrackParams::default();
+        assert_eq!(tones(&mutated(1234, off)), [0; 128]);
+
+        let locked = TrackParams {
+            mutate: Val(100),
+            mutate_lock: true,
+            ..Default::default()
+        };
+        assert_eq!(tones(&mutated(1234, locked)), [0; 128]);
+    }
+}
@@ -0,0 +1,86 @@
+use core::ops::Range;
+
+use crate::music::Scale;
+use crate::track::{Track, TrackStep};
+use crate::val::Val;
+
+/// Edits of the steps in a track.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    /// Move the steps of the play range one step left, the first wraps to the end.
+    ShiftLeft,
+    /// Move the steps of the play range one step right, the last wraps to the start.
+    ShiftRight,
+    /// Reverse the steps of the selected part that are in the play range.
+    Reverse,
+    /// Mirror the tones of the play range around the root.
+    Invert,
+    /// Move the tones of the play range one degree up in the scale.
+    TransposeUp,
+    /// Move the tones of the play range one degree down in the scale.
+    TransposeDown,
+}
+
+impl Transform {
+    /// Transform the steps, which are the range() of a track.
+    pub fn apply(&self, steps: &mut [TrackStep], scale: Scale) {
+        match self {
+            Transform::ShiftLeft => steps.rotate_left(1),
+            Transform::ShiftRight => steps.rotate_right(1),
+            Transform::Reverse => steps.reverse(),
+            Transform::Invert => {
+                // The tone range is symmetric, so this can't clamp.
+                for step in steps {
+                    step.tone = Val(-*step.tone);
+                }
+            }
+            Transform::TransposeUp | Transform::TransposeDown => {
+                let up = *self == Transform::TransposeUp;
+                for step in steps {
+                    step.tone.add(degree_offset(*step.tone, scale, up));
+                }
+            }
+        }
+    }
+
+    /// The transform that takes the steps back, if there is one. Transposing
+    /// can't be reversed, since tones off the scale end up on it.
+    pub fn inverse(&self) -> Option<Transform> {
+        match self {
+            Transform::ShiftLeft => Some(Transform::ShiftRight),
+            Transform::ShiftRight => Some(Transform::ShiftLeft),
+            Transform::Reverse => Some(Transform::Reverse),
+            Transform::Invert => Some(Transform::Invert),
+            Transform::TransposeUp | Transform::TransposeDown => None,
+        }
+    }
+
+    /// The steps of the track the transform works on.
+    pub fn range(&self, track: &Track, part: usize) -> Range<usize> {
+        let (first, len) = track.params.play_range();
+
+        match self {
+            Transform::Reverse => {
+                let start = (part * 16).max(first);
+                let end = (part * 16 + 16).min(first + len);
+                start..end.max(start)
+            }
+            _ => first..(first + len),
+        }
+    }
+}
+
+/// Semitones from tone to the next tone up or down that is in the scale.
+fn degree_offset(tone: i8, scale: Scale, up: bool) -> i8 {
+    let tones = scale.as_tones();
+    let dir = if up { 1 } else { -1 };
+
+    // Every scale has a tone within the octave.
+    (1..=12)
+        .map(|n| n * dir)
+        .find(|n| {
+            let key = (tone as i16 + *n as i16).rem_euclid(12) as i8;
+            tones.iter().any(|t| **t == key)
+        })
+        .unwrap_or(0)
+}
@@ -0,0 +1,146 @@
+use crate::led_grid::BiLed;
+use crate::music::{Scale, Tone};
+use crate::settings::{ClockSource, Ppqn, ResetMode, StepsPerBeat};
+use crate::track::{ClockRatio, TrackDirection, TrackSync, TrigCondition};
+use crate::val::Val;
+
+/// Time to show a value on the step LEDs after it was edited.
+pub const VIEW_MILLIS: i64 = 1000;
+
+/// A value being edited, shown on the 16 step LEDs (row 0-1).
+#[derive(Clone, Copy)]
+pub enum ValueView {
+    /// Bar filling up from the left. Value and max, where 0 is nothing lit.
+    Bar(i32, i32),
+
+    /// Bar going out from the centre, right for positive (green) and left for
+    /// negative (red). Value, min and max, where min < 0 < max.
+    Bipolar(i32, i32, i32),
+
+    /// One of a number of LEDs lit. Index and count.
+    OneHot(usize, usize),
+}
+
+/// Something that can be shown as a [`ValueView`].
+pub trait AsView {
+    fn as_view(&self) -> ValueView;
+}
+
+impl ValueView {
+    pub fn render(&self, leds: &mut [[BiLed; 8]; 5]) {
+        let mut steps = [BiLed::Off; 16];
+
+        match *self {
+            ValueView::Bar(v, max) => {
+                // Round up so that anything above 0 lights at least one LED.
+                let lit = ((v * 16 + max - 1) / max).clamp(0, 16) as usize;
+                steps[..lit].fill(BiLed::Grn);
+            }
+
+            ValueView::Bipolar(v, min, max) => {
+                if v > 0 {
+                    let lit = ((v * 8 + max - 1) / max).clamp(0, 8) as usize;
+                    steps[8..(8 + lit)].fill(BiLed::Grn);
+                } else if v < 0 {
+                    let lit = ((v * 8 + min + 1) / min).clamp(0, 8) as usize;
+                    steps[(8 - lit)..8].fill(BiLed::Red);
+                }
+            }
+
+            ValueView::OneHot(i, count) => {
+                if count <= 16 && i < count {
+                    steps[..count].fill(BiLed::Red);
+                    steps[i] = BiLed::Grn;
+                }
+            }
+        }
+
+        leds[0].copy_from_slice(&steps[..8]);
+        leds[1].copy_from_slice(&steps[8..]);
+    }
+}
+
+impl<const S: i8, const T: i8> AsView for Val<S, T> {
+    fn as_view(&self) -> ValueView {
+        let v = self.0 as i32;
+        let (s, t) = (S as i32, T as i32);
+
+        if s < 0 && t > 0 {
+            ValueView::Bipolar(v, s, t)
+        } else {
+            ValueView::Bar(v - s, t - s)
+        }
+    }
+}
+
+impl AsView for Scale {
+    fn as_view(&self) -> ValueView {
+        ValueView::OneHot(*self as usize, 11)
+    }
+}
+
+impl AsView for Tone {
+    fn as_view(&self) -> ValueView {
+        // Key within the octave.
+        ValueView::OneHot(self.0.rem_euclid(12) as usize, 12)
+    }
+}
+
+impl AsView for TrackSync {
+    fn as_view(&self) -> ValueView {
+        ValueView::OneHot(*self as usize, 4)
+    }
+}
+
+impl AsView for bool {
+    fn as_view(&self) -> ValueView {
+        ValueView::OneHot(*self as usize, 2)
+    }
+}
+
+impl AsView for ClockSource {
+    fn as_view(&self) -> ValueView {
+        ValueView::OneHot(*self as usize, 3)
+    }
+}
+
+impl AsView for Ppqn {
+    fn as_view(&self) -> ValueView {
+        ValueView::OneHot(*self as usize, 6)
+    }
+}
+
+impl AsView for StepsPerBeat {
+    fn as_view(&self) -> ValueView {
+        ValueView::OneHot(*self as usize, 4)
+    }
+}
+
+impl AsView for ClockRatio {
+    fn as_view(&self) -> ValueView {
+        ValueView::OneHot(*self as usize, 11)
+    }
+}
+
+impl AsView for ResetMode {
+    fn as_view(&self) -> ValueView {
+        ValueView::OneHot(*self as usize, 6)
+    }
+}
+
+impl AsView for TrackDirection {
+    fn as_view(&self) -> ValueView {
+        ValueView::OneHot(*self as usize, 6)
+    }
+}
+
+impl AsView for TrigCondition {
+    fn as_view(&self) -> ValueView {
+        match *self {
+            // A of B LEDs in the upper row.
+            TrigCondition::Iteration(a, b) => ValueView::OneHot(a as usize - 1, b as usize),
+            // The others in the lower row, with all 16 LEDs lit to tell them apart.
+            _ => ValueView::OneHot(8 + self.index(), 16),
+        }
+    }
+}